@@ -0,0 +1,125 @@
+//! Relays ICE candidates from a viewer to its sharer through a server on an ephemeral port, the
+//! busiest path of a session.
+
+use clap::Parser;
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use signaller::args::Args;
+use signaller::config::Config;
+use signaller::Server;
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// ICE candidates relayed per iteration.
+const CANDIDATES: usize = 10_000;
+
+async fn connect(addr: std::net::SocketAddr) -> Client {
+    let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/"))
+        .await
+        .expect("client can connect");
+    client
+}
+
+/// The next message of type `kind`, skipping any others.
+async fn recv(client: &mut Client, kind: &str) -> Value {
+    loop {
+        let frame = client
+            .next()
+            .await
+            .expect("connection is open")
+            .expect("frame can be read");
+        let Message::Text(text) = frame else {
+            continue;
+        };
+        let msg: Value = serde_json::from_str(&text).expect("message is JSON");
+        if msg["type"] == kind {
+            return msg;
+        }
+    }
+}
+
+/// Start a server that lets a viewer send as fast as it likes, and a room with one viewer in it.
+async fn start_session() -> (Client, Client, String) {
+    let burst = CANDIDATES.to_string();
+    let args = Args::parse_from([
+        "signaller",
+        "--ip-hash-salt",
+        "dGVzdHNhbHQ",
+        "--message-rate",
+        "1000000000",
+        "--message-burst",
+        &burst,
+        "--send-queue-size",
+        &burst,
+    ]);
+    let config = Config {
+        twilio_account_sid: None,
+        twilio_auth_token: None,
+        ice_servers: vec![],
+    };
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("listener can be bound");
+    let addr = listener.local_addr().expect("listener has an address");
+    tokio::spawn(Server::new(args, config).run(listener));
+
+    let mut sharer = connect(addr).await;
+    send(&mut sharer, json!({"type": "start"}).to_string()).await;
+    let room = recv(&mut sharer, "start_response").await["room"]
+        .as_str()
+        .expect("room is a string")
+        .to_string();
+    let mut viewer = connect(addr).await;
+    send(
+        &mut viewer,
+        json!({"type": "join", "from": "viewer", "room": room}).to_string(),
+    )
+    .await;
+    recv(&mut sharer, "peer_joined").await;
+    (sharer, viewer, room)
+}
+
+async fn send(client: &mut Client, text: String) {
+    client
+        .send(Message::Text(text))
+        .await
+        .expect("message can be sent");
+}
+
+/// Send `CANDIDATES` ICE candidates from `viewer` and wait until `sharer` has them all.
+async fn forward_candidates(sharer: &mut Client, viewer: &mut Client, room: &str) {
+    let sending = async {
+        for i in 0..CANDIDATES {
+            let candidate = format!("candidate:{i} 1 udp 1 127.0.0.1 9 typ host");
+            let ice = json!({"type": "ice", "from": "viewer", "to": room, "candidate": {"candidate": candidate, "sdpMid": "0"}});
+            send(viewer, ice.to_string()).await;
+        }
+    };
+    let receiving = async {
+        for _ in 0..CANDIDATES {
+            recv(sharer, "ice").await;
+        }
+    };
+    tokio::join!(sending, receiving);
+}
+
+fn forward_ice(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("runtime can be started");
+    let (mut sharer, mut viewer, room) = runtime.block_on(start_session());
+    c.bench_function("forward 10k ice candidates", |b| {
+        b.iter(|| runtime.block_on(forward_candidates(&mut sharer, &mut viewer, &room)))
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = forward_ice
+}
+criterion_main!(benches);
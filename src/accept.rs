@@ -0,0 +1,116 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::PollSemaphore;
+
+use crate::metrics::ACCEPTED_CONNECTIONS;
+
+/// Accepts connections from a listener only while a permit is free, so a flood of connections
+/// waits in the listen backlog instead of each getting a task. Every accepted connection holds its
+/// permit until it closes.
+pub struct LimitedIncoming {
+    incoming: AddrIncoming,
+    permits: PollSemaphore,
+    /// Acquired while waiting for the next connection.
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl LimitedIncoming {
+    pub fn new(incoming: AddrIncoming, permits: Arc<Semaphore>) -> LimitedIncoming {
+        LimitedIncoming {
+            incoming,
+            permits: PollSemaphore::new(permits),
+            permit: None,
+        }
+    }
+}
+
+impl Accept for LimitedIncoming {
+    type Conn = LimitedStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<LimitedStream>>> {
+        if self.permit.is_none() {
+            // The semaphore is never closed.
+            self.permit = ready!(self.permits.poll_acquire(cx));
+        }
+        let stream = match ready!(Pin::new(&mut self.incoming).poll_accept(cx)) {
+            Some(Ok(stream)) => stream,
+            Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+            None => return Poll::Ready(None),
+        };
+        ACCEPTED_CONNECTIONS.inc();
+        Poll::Ready(Some(Ok(LimitedStream {
+            stream,
+            _permit: self.permit.take(),
+        })))
+    }
+}
+
+/// A connection accepted by `LimitedIncoming`, which gives its permit back when dropped.
+pub struct LimitedStream {
+    stream: AddrStream,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl LimitedStream {
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.stream.remote_addr()
+    }
+}
+
+impl Drop for LimitedStream {
+    fn drop(&mut self) {
+        ACCEPTED_CONNECTIONS.dec();
+    }
+}
+
+impl AsyncRead for LimitedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for LimitedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
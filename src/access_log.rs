@@ -0,0 +1,43 @@
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// How handled messages are logged, on top of the regular logs.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccessLog {
+    /// Only the regular human-readable logs.
+    #[default]
+    Text,
+    /// Also print one JSON line per handled message to stdout.
+    Json,
+}
+
+/// One line of the JSON access log.
+#[derive(Serialize)]
+pub struct AccessLogEntry<'a> {
+    pub peer: Option<&'a str>,
+    pub room: Option<&'a str>,
+    #[serde(rename = "type")]
+    pub kind: Option<&'a str>,
+    pub bytes: usize,
+    /// Whether the message was handled, and forwarded if it was meant for another peer.
+    pub ok: bool,
+}
+
+impl AccessLogEntry<'_> {
+    pub fn write(&self) {
+        #[derive(Serialize)]
+        struct Line<'a, 'b> {
+            timestamp: String,
+            #[serde(flatten)]
+            entry: &'a AccessLogEntry<'b>,
+        }
+        let line = Line {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            entry: self,
+        };
+        println!("{}", serde_json::to_string(&line).unwrap());
+    }
+}
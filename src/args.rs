@@ -1,12 +1,333 @@
-use clap::Parser;
+use std::ffi::OsString;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context};
+use argon2::password_hash::SaltString;
+use clap::builder::Resettable;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command, CommandFactory, Parser};
+use warp::http::HeaderValue;
+
+use crate::access_log::AccessLog;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// Listening address
-    #[arg(short, long, default_value = "0.0.0.0:8080")]
-    pub(crate) address: String,
-    /// Salt for hashing IP addresses
-    #[arg(short, long)]
+    /// TOML file setting any of these options by name, e.g. max_viewers = 10. Options given on
+    /// the command line take precedence
+    #[arg(long)]
+    pub(crate) config: Option<PathBuf>,
+    /// Listening addresses, comma separated, e.g. 0.0.0.0:8080,[::]:8080 for dual stack
+    #[arg(
+        short,
+        long,
+        visible_alias = "address",
+        short_alias = 'a',
+        default_value = "0.0.0.0:8080",
+        value_delimiter = ','
+    )]
+    pub(crate) bind: Vec<SocketAddr>,
+    /// Salt for hashing IP addresses (unpadded base64, at least 4 characters)
+    #[arg(short, long, value_parser = parse_salt)]
     pub(crate) ip_hash_salt: String,
+    /// Log filter used when RUST_LOG is not set
+    #[arg(long, default_value = "info")]
+    pub(crate) log_level: String,
+    /// Extra per-message logging: "json" prints one JSON line per handled message to stdout
+    #[arg(long, value_enum, default_value_t = AccessLog::Text)]
+    pub(crate) access_log: AccessLog,
+    /// Seconds between websocket pings. Connections that miss two in a row are closed
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) ping_interval: u64,
+    /// Seconds a single message may take to be written to a client before it is dropped as stuck
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) write_timeout: u64,
+    /// Set SO_REUSEPORT on the listening sockets, so a new instance can bind the same port while
+    /// the old one is still draining. Not supported with TLS
+    #[arg(long, conflicts_with = "cert")]
+    pub(crate) reuse_port: bool,
+    /// Milliseconds clients are asked to wait before reconnecting when the server is full or
+    /// shutting down
+    #[arg(long, default_value_t = 5000)]
+    pub(crate) reconnect_delay_ms: u64,
+    /// Up to this many milliseconds are added at random to --reconnect-delay-ms for each client,
+    /// so they don't all come back at once
+    #[arg(long, default_value_t = 5000)]
+    pub(crate) reconnect_jitter_ms: u64,
+    /// TLS certificate chain (PEM). Serves wss:// when given together with --key
+    #[arg(long, requires = "key")]
+    pub(crate) cert: Option<PathBuf>,
+    /// TLS private key (PEM)
+    #[arg(long, requires = "cert")]
+    pub(crate) key: Option<PathBuf>,
+    /// CA certificates (PEM) that client certificates must be signed by. Clients without one are
+    /// refused during the TLS handshake. Needs --cert and --key
+    #[arg(long, requires = "cert")]
+    pub(crate) tls_client_ca: Option<PathBuf>,
+    /// Comma-separated namespaces served on /ns/<name> besides the default one on /. Rooms and
+    /// peers of different namespaces can't see each other
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) namespaces: Vec<String>,
+    /// Maximum number of rooms open at once, in each namespace. Unlimited if not given
+    #[arg(long)]
+    pub(crate) max_rooms: Option<usize>,
+    /// Maximum number of viewers per room. Unlimited if not given
+    #[arg(long)]
+    pub(crate) max_viewers: Option<usize>,
+    /// Largest websocket message or frame accepted from a client, in bytes
+    #[arg(long, default_value_t = 256 * 1024)]
+    pub(crate) max_message_size: usize,
+    /// Serve /metrics on this address instead of the listening address
+    #[arg(long)]
+    pub(crate) metrics_addr: Option<SocketAddr>,
+    /// Serve a /healthz liveness probe on this address
+    #[arg(long)]
+    pub(crate) health_addr: Option<SocketAddr>,
+    /// Seconds a disconnected sharer's room is kept so they can resume it. 0 disables resuming
+    #[arg(long, default_value_t = 15)]
+    pub(crate) resume_grace: u64,
+    /// Seconds a room may go without viewers before it is closed. Unlimited if not given
+    #[arg(long)]
+    pub(crate) empty_room_ttl: Option<u64>,
+    /// Seconds a room may stay open in total, however busy. Unlimited if not given
+    #[arg(long)]
+    pub(crate) max_session_duration: Option<u64>,
+    /// Seconds before --max-session-duration is reached that the room's peers are warned
+    #[arg(long, default_value_t = 300)]
+    pub(crate) session_expiry_warning: u64,
+    /// Seconds a connection may stay open without starting or joining a room. Unlimited if not
+    /// given
+    #[arg(long)]
+    pub(crate) register_timeout: Option<u64>,
+    /// Maximum number of connections that haven't started or joined a room yet. New connections
+    /// beyond it are refused. Unlimited if not given
+    #[arg(long)]
+    pub(crate) max_unregistered: Option<usize>,
+    /// Seconds a viewer may wait for a room that hasn't been started yet. 0 disables waiting
+    #[arg(long, default_value_t = 0)]
+    pub(crate) join_grace: u64,
+    /// Require Start and Join to carry a token signed with this HMAC-SHA256 secret
+    #[arg(long)]
+    pub(crate) auth_secret: Option<String>,
+    /// Let websocket clients that send AdminSubscribe with this token receive live server stats
+    #[arg(long)]
+    pub(crate) admin_token: Option<String>,
+    /// Comma-separated message types viewers may send, e.g. join,answer,ice. leave and keep_alive
+    /// are always allowed. Any type is allowed if not given
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) viewer_allowed_messages: Vec<String>,
+    /// Comma-separated room names sharers may not choose
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) reserved_rooms: Vec<String>,
+    /// Comma-separated room names sharers may choose. Any name is allowed if not given
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) room_allowlist: Vec<String>,
+    /// JSON file listing STUN/TURN servers to hand out to peers
+    #[arg(long)]
+    pub(crate) ice_servers: Option<PathBuf>,
+    /// Comma-separated addresses or CIDR ranges of reverse proxies trusted to report the client's
+    /// IP in X-Forwarded-For, e.g. 10.0.0.0/8. The header is ignored on connections from anywhere
+    /// else
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) trusted_proxies: Vec<IpRange>,
+    /// New connections allowed per second from a single IP
+    #[arg(long, default_value_t = 1.0)]
+    pub(crate) connection_rate: f64,
+    /// Connections a single IP may open in a burst before --connection-rate applies
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) connection_burst: u32,
+    /// Offers, answers, ICE candidates and other forwarded messages a connection may send per
+    /// second before the excess is dropped
+    #[arg(long, default_value_t = 50.0)]
+    pub(crate) message_rate: f64,
+    /// Forwarded messages a connection may send in a burst before --message-rate applies
+    #[arg(long, default_value_t = 200, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) message_burst: u32,
+    /// Messages that may queue up for a peer before it is dropped as too slow. Queue depths are
+    /// exported as send_queue_depth and max_send_queue_depth
+    #[arg(long, default_value_t = 256, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) send_queue_size: u32,
+    /// Maximum number of TCP connections served at once. Further connections wait in the listen
+    /// backlog until one closes. Unlimited if not given. Not supported with TLS
+    #[arg(long, conflicts_with = "cert")]
+    pub(crate) max_accepted: Option<usize>,
+    /// Maximum number of concurrent websocket connections. Unlimited if not given
+    #[arg(long)]
+    pub(crate) max_connections: Option<usize>,
+    /// Server header sent when upgrading to a websocket. Empty to leave it out
+    #[arg(
+        long,
+        default_value = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+        value_parser = parse_header_value
+    )]
+    pub(crate) server_header: String,
+    /// Comma-separated origins allowed to open a websocket, e.g. https://example.com. "*" allows any
+    #[arg(long, value_delimiter = ',', default_value = "*")]
+    pub(crate) allowed_origins: Vec<String>,
+}
+
+impl Args {
+    /// Parse the command line, taking options it doesn't set from the `--config` file if given.
+    /// Exits with a usage message on bad flags, like `Args::parse`.
+    pub fn load() -> anyhow::Result<Args> {
+        Args::load_from(std::env::args_os())
+    }
+
+    pub fn load_from<I, T>(args: I) -> anyhow::Result<Args>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString>,
+    {
+        let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        // Only to find the config file and what the command line sets, the real parse comes last.
+        let Ok(matches) = lenient_command()
+            .ignore_errors(true)
+            .try_get_matches_from(&args)
+        else {
+            return Ok(Args::parse_from(args));
+        };
+        let Some(path) = matches.get_one::<PathBuf>("config") else {
+            return Ok(Args::parse_from(args));
+        };
+        let from_file = config_file_args(path, &matches)?;
+        let mut args = args.into_iter();
+        let merged: Vec<OsString> = args
+            .next()
+            .into_iter()
+            .chain(from_file)
+            .chain(args)
+            .collect();
+        Ok(Args::parse_from(merged))
+    }
+
+    /// Whether listeners are bound with SO_REUSEPORT.
+    pub fn reuse_port(&self) -> bool {
+        self.reuse_port
+    }
+}
+
+/// The command without any required or interdependent arguments, so parts of the full set of
+/// flags can be parsed on their own.
+fn lenient_command() -> Command {
+    Args::command().mut_args(|arg| arg.required(false).requires(Resettable::Reset))
+}
+
+/// Turn the config file into flags, leaving out options `matches` got from the command line.
+fn config_file_args(path: &Path, matches: &ArgMatches) -> anyhow::Result<Vec<OsString>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("reading config from {}", path.display()))?;
+    let table: toml::Table = toml::from_str(&contents)
+        .with_context(|| format!("parsing config from {}", path.display()))?;
+    let command = Args::command();
+    let mut args = vec![];
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()) && arg.get_id() != "config")
+            .ok_or_else(|| anyhow!("{}: unknown option `{}`", path.display(), key))?;
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        // Switches take no value, so only `true` turns them on.
+        if matches!(arg.get_action(), ArgAction::SetTrue) {
+            match value {
+                toml::Value::Boolean(true) => args.push(OsString::from(format!("--{long}"))),
+                toml::Value::Boolean(false) => {}
+                _ => bail!("{}: `{}` must be true or false", path.display(), key),
+            }
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) if matches!(arg.get_action(), ArgAction::Append) => values,
+            toml::Value::Array(_) => bail!("{}: `{}` takes a single value", path.display(), key),
+            value => vec![value],
+        };
+        let mut key_args = vec![];
+        for value in values {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                    value.to_string()
+                }
+                _ => bail!("{}: `{}` must be a string or a number", path.display(), key),
+            };
+            key_args.push(OsString::from(format!("--{long}")));
+            key_args.push(OsString::from(value));
+        }
+        // Check each option by itself so a bad value can be blamed on its key.
+        lenient_command()
+            .try_get_matches_from(std::iter::once(OsString::new()).chain(key_args.clone()))
+            .map_err(|e| {
+                let e = e.to_string();
+                let reason = e.lines().next().unwrap_or_default();
+                anyhow!(
+                    "{}: invalid `{}`: {}",
+                    path.display(),
+                    key,
+                    reason.trim_start_matches("error: ")
+                )
+            })?;
+        args.extend(key_args);
+    }
+    Ok(args)
+}
+
+fn parse_header_value(value: &str) -> Result<String, String> {
+    HeaderValue::from_str(value).map_err(|_| "not a valid header value".to_string())?;
+    Ok(value.to_string())
+}
+
+fn parse_salt(salt: &str) -> Result<String, String> {
+    SaltString::from_b64(salt).map_err(|e| format!("invalid salt: {}", e))?;
+    Ok(salt.to_string())
+}
+
+/// An IP address or CIDR range, e.g. 10.0.0.0/8. A bare address is a range of one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct IpRange {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl IpRange {
+    pub(crate) fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(addr) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(addr) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpRange, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("not an IP address: {addr}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max)
+                .ok_or_else(|| format!("prefix length must be 0 to {max}, got {prefix}"))?,
+            None => max,
+        };
+        Ok(IpRange { addr, prefix })
+    }
 }
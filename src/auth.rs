@@ -0,0 +1,65 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::error::{Result, SignallerError};
+
+/// What a token lets its holder do.
+#[derive(Deserialize)]
+struct Claims {
+    /// Unix time in seconds after which the token is no longer accepted.
+    exp: u64,
+    /// The only room the token may be used for, if any.
+    #[serde(default)]
+    room: Option<String>,
+}
+
+/// Check a `<claims>.<signature>` token, where the claims are JSON and the signature is the
+/// HMAC-SHA256 of the encoded claims under `secret`, both unpadded base64url. The token must not
+/// have expired and, if it names a room, must be used for that `room`.
+pub fn verify_token(secret: &str, token: Option<&str>, room: Option<&str>) -> Result<()> {
+    let token = token.ok_or(SignallerError::Unauthorized)?;
+    let (claims, signature) = token.split_once('.').ok_or(SignallerError::Unauthorized)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| SignallerError::Unauthorized)?;
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(claims.as_bytes());
+    mac.verify_slice(&signature)
+        .map_err(|_| SignallerError::Unauthorized)?;
+
+    let claims: Claims = URL_SAFE_NO_PAD
+        .decode(claims)
+        .ok()
+        .and_then(|claims| serde_json::from_slice(&claims).ok())
+        .ok_or(SignallerError::Unauthorized)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if claims.exp <= now {
+        return Err(SignallerError::Unauthorized);
+    }
+    if claims
+        .room
+        .is_some_and(|allowed| room != Some(allowed.as_str()))
+    {
+        return Err(SignallerError::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Compare an admin token in constant time, so how much of it is right can't be timed.
+pub fn admin_token_matches(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
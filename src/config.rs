@@ -2,8 +2,12 @@ use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
 
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
+use crate::args::Args;
+use crate::signaller_message::IceServer;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     #[serde()]
@@ -11,10 +15,14 @@ pub struct Config {
 
     #[serde()]
     pub twilio_auth_token: Option<String>,
+
+    /// Static STUN/TURN servers handed to every peer, alongside any from Twilio.
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
 }
 
 #[allow(dead_code)]
-pub fn load(path: &Path) -> Result<Config, failure::Error> {
+pub fn load(path: &Path) -> anyhow::Result<Config> {
     // create a new file if it does not exist
     if !path.exists() {
         let mut file = File::create(path)?;
@@ -33,5 +41,23 @@ pub fn from_env() -> Config {
     Config {
         twilio_account_sid: std::env::var("TWILIO_ACCOUNT_SID").ok(),
         twilio_auth_token: std::env::var("TWILIO_AUTH_TOKEN").ok(),
+        ice_servers: vec![],
     }
 }
+
+/// The config from the environment, with the ICE servers from `--ice-servers` if given.
+pub fn from_args(args: &Args) -> anyhow::Result<Config> {
+    let mut config = from_env();
+    if let Some(path) = &args.ice_servers {
+        config.ice_servers = load_ice_servers(path)?;
+    }
+    Ok(config)
+}
+
+/// Read a JSON list of ICE servers, e.g. `[{"urls": "turn:...", "username": "u", "credential": "p"}]`.
+pub fn load_ice_servers(path: &Path) -> anyhow::Result<Vec<IceServer>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("reading ICE servers from {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("parsing ICE servers from {}", path.display()))
+}
@@ -0,0 +1,134 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tokio::sync::Notify;
+use tracing::info;
+
+/// A live websocket connection, whether or not it has registered as a peer.
+pub struct ConnectionInfo {
+    pub addr: SocketAddr,
+    pub connected_at: Instant,
+    /// The peer id it registered with a `Start`, `Join` or `Resume`, if any.
+    pub peer: Option<String>,
+    /// Whether it subscribed to `AdminStats`, which counts as registering.
+    pub admin: bool,
+    /// Room passwords it got wrong.
+    password_failures: u32,
+    /// Woken to make the connection hang up.
+    close: Arc<Notify>,
+}
+
+impl ConnectionInfo {
+    fn registered(&self) -> bool {
+        self.peer.is_some() || self.admin
+    }
+}
+
+/// Every open websocket connection, by an id assigned when it is accepted. Never locked together
+/// with the maps in `State`.
+#[derive(Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    connections: DashMap<u64, ConnectionInfo>,
+    /// Connections in `connections` that haven't registered, kept up to date so it can be checked
+    /// on every handshake.
+    unregistered: AtomicUsize,
+}
+
+impl ConnectionRegistry {
+    /// Track a newly accepted connection. It should hang up when the returned `Notify` is woken.
+    pub fn open(&self, addr: SocketAddr) -> (u64, Arc<Notify>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let close = Arc::new(Notify::new());
+        self.unregistered.fetch_add(1, Ordering::Relaxed);
+        self.connections.insert(
+            id,
+            ConnectionInfo {
+                addr,
+                connected_at: Instant::now(),
+                peer: None,
+                admin: false,
+                password_failures: 0,
+                close: close.clone(),
+            },
+        );
+        (id, close)
+    }
+
+    pub fn close(&self, id: u64) {
+        if let Some((_, connection)) = self.connections.remove(&id) {
+            if !connection.registered() {
+                self.unregistered.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Record the peer id connection `id` is registered as, if it changed.
+    pub fn set_peer(&self, id: u64, peer: Option<&str>) {
+        self.update(id, |connection| {
+            if connection.peer.as_deref() != peer {
+                connection.peer = peer.map(str::to_string);
+            }
+        });
+    }
+
+    pub fn set_admin(&self, id: u64) {
+        self.update(id, |connection| connection.admin = true);
+    }
+
+    /// Change connection `id`, keeping count of whether it is registered.
+    fn update(&self, id: u64, change: impl FnOnce(&mut ConnectionInfo)) {
+        let Some(mut connection) = self.connections.get_mut(&id) else {
+            return;
+        };
+        let was_registered = connection.registered();
+        change(&mut connection);
+        match (was_registered, connection.registered()) {
+            (false, true) => {
+                self.unregistered.fetch_sub(1, Ordering::Relaxed);
+            }
+            (true, false) => {
+                self.unregistered.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    /// Count a wrong room password from connection `id`, returning how many it has sent so far.
+    pub fn password_failed(&self, id: u64) -> u32 {
+        let Some(mut connection) = self.connections.get_mut(&id) else {
+            return 0;
+        };
+        connection.password_failures += 1;
+        connection.password_failures
+    }
+
+    pub fn is_admin(&self, id: u64) -> bool {
+        self.connections
+            .get(&id)
+            .is_some_and(|connection| connection.admin)
+    }
+
+    /// How many connections have yet to register.
+    pub fn unregistered(&self) -> usize {
+        self.unregistered.load(Ordering::Relaxed)
+    }
+
+    /// Hang up on connections that have been open longer than `timeout` without registering.
+    /// Called periodically.
+    pub fn reap_unregistered(&self, timeout: Duration) {
+        for connection in self.connections.iter() {
+            if !connection.registered() && connection.connected_at.elapsed() > timeout {
+                info!(
+                    "{} didn't start or join a room within {}s, closing it",
+                    connection.addr,
+                    timeout.as_secs()
+                );
+                connection.close.notify_one();
+            }
+        }
+    }
+}
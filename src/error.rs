@@ -0,0 +1,123 @@
+use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
+use warp::ws::Message;
+
+pub type Result<T> = std::result::Result<T, SignallerError>;
+
+#[derive(Debug, Error)]
+pub enum SignallerError {
+    #[error("room already exists")]
+    RoomExists,
+    #[error("the server has reached its limit of rooms")]
+    ServerAtCapacity,
+    #[error("room does not exist")]
+    RoomNotFound,
+    #[error("room name is already taken")]
+    NameTaken,
+    #[error("room name is not allowed")]
+    RoomForbidden,
+    #[error("Peer does not exist")]
+    PeerNotFound,
+    #[error("peer has disconnected")]
+    PeerGone,
+    #[error("room is full")]
+    RoomFull,
+    #[error("waiting to be admitted to the room")]
+    AwaitingAdmission,
+    #[error("the sharer declined to admit you")]
+    AdmissionDenied,
+    #[error("room is closing")]
+    SessionClosing,
+    #[error("Peer id is already in use")]
+    PeerExists,
+    #[error("already a sharer of this room")]
+    AlreadySharer,
+    #[error("peers are not in the same room")]
+    NotInRoom,
+    #[error("answers must go from a viewer to a sharer of its room")]
+    BadRoute,
+    #[error("sender does not match this connection")]
+    SenderMismatch,
+    #[error("messages can't be addressed to their own sender")]
+    SelfAddressed,
+    #[error("invalid room password")]
+    InvalidPassword,
+    #[error("invalid resume token")]
+    InvalidResumeToken,
+    #[error("the sharer of this room is still connected")]
+    SharerConnected,
+    #[error("this connection has not started or joined a room")]
+    NotRegistered,
+    #[error("this connection has already started or joined a room")]
+    AlreadyRegistered,
+    #[error("missing, invalid or expired auth token")]
+    Unauthorized,
+    #[error("only the sharer of the room can do this")]
+    NotSharer,
+    #[error("only viewers can do this")]
+    NotViewer,
+    #[error("unknown quality level: {0}")]
+    UnknownQualityLevel(String),
+    #[error("spectators can't send signalling messages")]
+    Forbidden,
+    #[error("viewers may not send {0} messages on this server")]
+    ViewerForbidden(String),
+    #[error("only sharers can ask the room to renegotiate")]
+    RenegotiateForbidden,
+    #[error("no such viewer in this room")]
+    ViewerNotFound,
+    #[error("too many messages, some were dropped")]
+    RateLimited,
+    #[error("unknown message type: {0}")]
+    UnknownMessageType(String),
+    #[error("invalid message: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("invalid message: {0}")]
+    MsgPack(#[from] rmp_serde::decode::Error),
+    #[error("error sending message: {0}")]
+    Send(#[from] TrySendError<Message>),
+    #[error("error hashing room password: {0}")]
+    PasswordHash(#[from] argon2::password_hash::Error),
+}
+
+impl SignallerError {
+    /// Machine-readable code sent to the client alongside the human-readable message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SignallerError::RoomExists => "room_exists",
+            SignallerError::ServerAtCapacity => "server_at_capacity",
+            SignallerError::RoomNotFound => "room_not_found",
+            SignallerError::NameTaken => "name_taken",
+            SignallerError::RoomForbidden => "room_forbidden",
+            SignallerError::PeerNotFound => "peer_not_found",
+            SignallerError::PeerGone => "peer_gone",
+            SignallerError::RoomFull => "room_full",
+            SignallerError::AwaitingAdmission => "awaiting_admission",
+            SignallerError::AdmissionDenied => "admission_denied",
+            SignallerError::SessionClosing => "session_closing",
+            SignallerError::PeerExists => "peer_exists",
+            SignallerError::AlreadySharer => "already_sharer",
+            SignallerError::NotInRoom => "not_in_room",
+            SignallerError::BadRoute => "bad_route",
+            SignallerError::SenderMismatch => "sender_mismatch",
+            SignallerError::SelfAddressed => "self_addressed",
+            SignallerError::InvalidPassword => "invalid_password",
+            SignallerError::InvalidResumeToken => "invalid_resume_token",
+            SignallerError::SharerConnected => "sharer_connected",
+            SignallerError::NotRegistered => "not_registered",
+            SignallerError::AlreadyRegistered => "already_registered",
+            SignallerError::Unauthorized => "unauthorized",
+            SignallerError::NotSharer => "not_sharer",
+            SignallerError::NotViewer => "not_viewer",
+            SignallerError::UnknownQualityLevel(_) => "unknown_quality_level",
+            SignallerError::Forbidden
+            | SignallerError::ViewerForbidden(_)
+            | SignallerError::RenegotiateForbidden => "forbidden",
+            SignallerError::ViewerNotFound => "viewer_not_found",
+            SignallerError::RateLimited => "rate_limited",
+            SignallerError::UnknownMessageType(_) => "unknown_message_type",
+            SignallerError::Serde(_) | SignallerError::MsgPack(_) => "invalid_message",
+            SignallerError::Send(_) | SignallerError::PasswordHash(_) => "internal_error",
+        }
+    }
+}
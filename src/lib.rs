@@ -0,0 +1,1212 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures_util::{future, stream::TryStreamExt, SinkExt, StreamExt};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn, Service};
+use rand::distributions::Distribution;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use socket2::{Domain, Socket, Type};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Mutex, Semaphore};
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+use warp::http::header::SERVER;
+use warp::http::{HeaderValue, StatusCode};
+use warp::ws::Message;
+use warp::ws::WebSocket;
+use warp::{Filter, Rejection, Reply};
+
+use crate::accept::{LimitedIncoming, LimitedStream};
+use crate::access_log::{AccessLog, AccessLogEntry};
+use crate::args::{Args, IpRange};
+use crate::config::Config;
+use crate::error::{Result, SignallerError};
+use crate::peer::{
+    send_message, PeerType, Tx, CLOSE_PASSWORD_FAILURES, CLOSE_PING_TIMEOUT,
+    CLOSE_POLICY_VIOLATION, CLOSE_REGISTER_TIMEOUT, CLOSE_TRY_AGAIN_LATER,
+};
+use crate::rate_limit::{MessageLimiter, RateLimiter};
+use crate::signaller_message::{Encoding, Protocol, SignallerMessage};
+use crate::state::{JoinStatus, StateType};
+
+mod accept;
+mod access_log;
+pub mod args;
+mod auth;
+pub mod config;
+mod connections;
+mod error;
+mod metrics;
+mod peer;
+mod rate_limit;
+mod session;
+mod signaller_message;
+mod state;
+mod twilio_helper;
+
+const ROOM_ID_LEN: usize = 5;
+/// How long to wait for a close frame to be flushed before dropping the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often rooms are checked against their time limits.
+const REAPER_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the peers of a room being drained get to reconnect before it is closed.
+const DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// How often admin subscribers are sent `AdminStats`.
+const ADMIN_STATS_INTERVAL: Duration = Duration::from_secs(5);
+/// How often idle entries are dropped from the connection rate limiter.
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// How long peers get to receive the shutdown notice before the process exits.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(2);
+/// How many messages in a row are dropped for exceeding `--message-rate` between each error
+/// telling the client about it.
+const RATE_LIMIT_ERROR_EVERY: u32 = 50;
+/// Wrong room passwords a connection may send before it is closed, so guessing takes a new
+/// connection, and with it a turn of the connection rate limiter, every few tries.
+const MAX_PASSWORD_FAILURES: u32 = 5;
+
+/// The client's IP as reported in X-Forwarded-For, if the connection came from a trusted proxy.
+/// Proxies add the address they got the request from, so the client is the last one that isn't
+/// a proxy itself.
+fn forwarded_client(peer: IpAddr, forwarded: &[IpAddr], trusted: &[IpRange]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|range| range.contains(ip));
+    if !is_trusted(&peer) {
+        return None;
+    }
+    forwarded
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or(forwarded.first())
+        .copied()
+}
+
+fn generate_room_id(len: usize) -> String {
+    pub struct UserFriendlyAlphabet;
+    impl Distribution<u8> for UserFriendlyAlphabet {
+        fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> u8 {
+            const GEN_ASCII_STR_CHARSET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+            GEN_ASCII_STR_CHARSET[(rng.next_u32() >> (32 - 5)) as usize]
+        }
+    }
+
+    thread_rng()
+        .sample_iter(&UserFriendlyAlphabet)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+async fn send_ice_servers(state: &StateType, tx: &Tx) {
+    let ice_servers = state.get_ice_servers().await;
+    send_message(tx, &SignallerMessage::IceServersResponse { ice_servers });
+}
+
+async fn handle_message(
+    state: &StateType,
+    tx: &Tx,
+    frame: &Message,
+    peer_id: &mut Option<String>,
+    connection_id: u64,
+    limiter: &mut MessageLimiter,
+) -> Result<()> {
+    let msg = tx.encoding.decode(frame)?;
+    // Reading the type means parsing the frame again, so only do it when there is a policy.
+    if state.viewer_allowed_messages.is_some() {
+        if let (Some(id), Some(kind)) = (peer_id.as_deref(), tx.encoding.message_type(frame)) {
+            state.check_viewer_message(id, &kind)?;
+        }
+    }
+    // Takes a token for a message that is about to be passed on to other peers. Over the limit
+    // the message is dropped, and only a sustained flood earns the client an error.
+    let mut throttle = || -> Result<bool> {
+        if limiter.check() {
+            return Ok(true);
+        }
+        metrics::MESSAGES_RATE_LIMITED.inc();
+        if limiter.dropped().is_multiple_of(RATE_LIMIT_ERROR_EVERY) {
+            return Err(SignallerError::RateLimited);
+        }
+        Ok(false)
+    };
+    let forward_message = |to: &str| -> Result<()> {
+        let (room, peer_type, encoding, sent) = {
+            let peer = state.peers.get(to).ok_or(SignallerError::PeerNotFound)?;
+            // Relay the frame untouched unless the recipient speaks a different encoding.
+            let frame = if peer.sender.encoding == tx.encoding {
+                frame.clone()
+            } else {
+                peer.sender.encoding.encode(&tx.encoding.decode(frame)?)
+            };
+            let encoding = peer.sender.encoding;
+            (
+                peer.room.clone(),
+                peer.peer_type,
+                encoding,
+                peer.sender.send(frame),
+            )
+        };
+        if let Err(TrySendError::Closed(frame)) = sent {
+            // The connection ended but hasn't been cleaned up yet. Sharers are left alone, their
+            // room may be waiting for them to resume, in which case the message is held for them.
+            info!("{} has gone away", to);
+            if !matches!(peer_type, PeerType::Sharer {}) {
+                state.cleanup_peer(to)?;
+            } else if state.buffer_for_resume(&room, to, encoding, frame) {
+                debug!("Holding message for {} until it resumes", to);
+                return Ok(());
+            }
+            return Err(SignallerError::PeerGone);
+        }
+        sent?;
+        debug!("Forwarded message to {}", to);
+        metrics::MESSAGES_FORWARDED.inc();
+        state.count_forward(&room);
+        Ok(())
+    };
+
+    // A connection is one peer. Registering again would strand the first registration, which
+    // nothing would clean up.
+    let ensure_unregistered = || match peer_id {
+        Some(_) => Err(SignallerError::AlreadyRegistered),
+        None => Ok(()),
+    };
+    let answer = matches!(
+        msg,
+        SignallerMessage::Answer { .. } | SignallerMessage::DataAnswer { .. }
+    );
+    match msg {
+        SignallerMessage::Join {
+            from,
+            room,
+            password,
+            spectator,
+            token,
+        } => {
+            let peer_type = match spectator {
+                true => PeerType::Spectator {},
+                false => PeerType::Viewer {},
+            };
+            let joined = match ensure_unregistered()
+                .and_then(|_| state.authorize(token.as_deref(), Some(&room)))
+            {
+                Ok(()) => {
+                    state
+                        .add_viewer(
+                            from.clone(),
+                            room.clone(),
+                            tx.clone(),
+                            password.as_deref(),
+                            peer_type,
+                        )
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match joined {
+                Ok(JoinStatus::Joined) => {
+                    info!("{} joined room {}", from, room);
+                    *peer_id = Some(from);
+                    send_ice_servers(state, tx).await;
+                }
+                Ok(JoinStatus::Waiting) => {
+                    *peer_id = Some(from);
+                    send_message(tx, &SignallerMessage::JoinPending { room });
+                    send_ice_servers(state, tx).await;
+                }
+                Err(e) => {
+                    info!("Error joining room: {}", e);
+                    if let SignallerError::RoomFull = e {
+                        send_message(tx, &retry(state, e.to_string()));
+                    }
+                    send_message(
+                        tx,
+                        &SignallerMessage::JoinDeclined {
+                            to: from,
+                            reason: e.to_string(),
+                            code: e.code().to_string(),
+                        },
+                    );
+                    if let SignallerError::InvalidPassword = e {
+                        if state.connections.password_failed(connection_id) >= MAX_PASSWORD_FAILURES
+                        {
+                            info!(
+                                "Closing connection after {MAX_PASSWORD_FAILURES} wrong passwords"
+                            );
+                            let _ = tx.send(Message::close_with(
+                                CLOSE_PASSWORD_FAILURES,
+                                "too many wrong passwords",
+                            ));
+                        }
+                    }
+                }
+            };
+        }
+        SignallerMessage::Start {
+            max_viewers,
+            password,
+            name,
+            require_admission,
+            token,
+        } => {
+            ensure_unregistered()?;
+            state.authorize(token.as_deref(), name.as_deref())?;
+            let password_hash = match password {
+                Some(password) => Some(state.hash_password(password).await?),
+                None => None,
+            };
+            let tries = 3;
+            let mut room = generate_room_id(ROOM_ID_LEN);
+            for _ in 0..tries {
+                if !state.sessions.contains_key(&room) && !state.peers.contains_key(&room) {
+                    break;
+                }
+                room = generate_room_id(ROOM_ID_LEN);
+            }
+            info!("New room: {}", room);
+            let resume_token = state
+                .add_sharer(
+                    room.clone(),
+                    tx.clone(),
+                    max_viewers,
+                    password_hash,
+                    name,
+                    require_admission,
+                )
+                .map_err(|e| {
+                    if let SignallerError::ServerAtCapacity = e {
+                        send_message(tx, &retry(state, e.to_string()));
+                    }
+                    e
+                })?;
+            *peer_id = Some(room.clone());
+            send_message(
+                tx,
+                &SignallerMessage::StartResponse {
+                    room: room.clone(),
+                    resume_token,
+                },
+            );
+            send_ice_servers(state, tx).await;
+            state.admit_early_viewers(&room).await;
+        }
+        SignallerMessage::Resume { room, token } => {
+            ensure_unregistered()?;
+            *peer_id = Some(state.resume_sharer(&room, &token, tx.clone())?);
+            send_message(tx, &SignallerMessage::Resumed { room });
+        }
+        SignallerMessage::Leave { from } => {
+            if peer_id.as_deref() != Some(from.as_str()) {
+                return Err(SignallerError::SenderMismatch);
+            }
+            info!("{} is leaving", from);
+            state.leave_session(from)?;
+            // The id is free again, and may be taken by someone this connection mustn't clean up.
+            *peer_id = None;
+        }
+        SignallerMessage::IceServers {} => send_ice_servers(state, tx).await,
+        SignallerMessage::AppPing { nonce } => {
+            send_message(tx, &SignallerMessage::AppPong { nonce });
+        }
+        SignallerMessage::MySessions { token } => {
+            let sessions = state.my_sessions(token.as_deref(), peer_id.as_deref());
+            send_message(tx, &SignallerMessage::SessionList { sessions });
+        }
+        SignallerMessage::AdminSubscribe { token } => {
+            state.subscribe_admin(&token, tx.clone())?;
+            state.connections.set_admin(connection_id);
+            info!("Admin subscribed to stats");
+        }
+        SignallerMessage::DrainRoom { room } => {
+            if !state.connections.is_admin(connection_id) {
+                return Err(SignallerError::Unauthorized);
+            }
+            let room = state.drain_room(room)?;
+            let state = state.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(DRAIN_GRACE_PERIOD).await;
+                state.finish_drain(&room);
+            });
+        }
+        SignallerMessage::ListRooms {} => {
+            let rooms = state.list_rooms();
+            send_message(tx, &SignallerMessage::RoomList { rooms });
+        }
+        SignallerMessage::WhoAmI {} => {
+            send_message(tx, &state.identity(peer_id.as_deref()));
+        }
+        SignallerMessage::ListViewers {} => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            send_message(tx, &state.list_viewers(id)?);
+        }
+        SignallerMessage::RoomStats {} => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            send_message(tx, &state.room_stats(id)?);
+        }
+        SignallerMessage::Kick { target } => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            state.kick(id, &target)?;
+        }
+        SignallerMessage::TransferSharer { target } => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            state.transfer_sharer(id, &target)?;
+        }
+        SignallerMessage::Broadcast { payload } => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            if !throttle()? {
+                return Ok(());
+            }
+            state.broadcast(id, payload)?;
+        }
+        SignallerMessage::Admit { target } => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            state.admit(id, &target)?;
+        }
+        SignallerMessage::Deny { target } => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            state.deny(id, &target)?;
+        }
+        SignallerMessage::Renegotiate {} => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            if !throttle()? {
+                return Ok(());
+            }
+            state.request_renegotiation(id)?;
+        }
+        SignallerMessage::QualityRequest { level } => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            if !throttle()? {
+                return Ok(());
+            }
+            state.request_quality(id, level)?;
+        }
+        SignallerMessage::PromoteToSharer { target } => {
+            let id = peer_id.as_deref().ok_or(SignallerError::NotRegistered)?;
+            state.promote_sharer(id, &target)?;
+        }
+        SignallerMessage::Offer { from, to, seq, .. }
+        | SignallerMessage::Answer { from, to, seq, .. }
+        | SignallerMessage::Ice { from, to, seq, .. }
+        | SignallerMessage::DataOffer { from, to, seq, .. }
+        | SignallerMessage::DataAnswer { from, to, seq, .. }
+        | SignallerMessage::DataIce { from, to, seq, .. } => {
+            // Ok(false) when the message was dropped by the rate limit.
+            let mut forward = || -> Result<bool> {
+                if peer_id.as_deref() != Some(from.as_str()) {
+                    return Err(SignallerError::SenderMismatch);
+                }
+                let Some(to) = &to else {
+                    // Only a sharer can address every viewer, and sharers don't answer.
+                    if answer {
+                        return Err(SignallerError::BadRoute);
+                    }
+                    let viewers = state.room_viewers(&from)?;
+                    if !throttle()? {
+                        return Ok(false);
+                    }
+                    // A viewer that has gone away mustn't keep the others from getting it.
+                    for viewer in &viewers {
+                        if let Err(e) = forward_message(viewer) {
+                            debug!("Error forwarding to {}: {}", viewer, e);
+                        }
+                    }
+                    return Ok(true);
+                };
+                if answer {
+                    state.check_answer_route(&from, to)?;
+                } else {
+                    state.check_route(&from, to)?;
+                }
+                if !throttle()? {
+                    return Ok(false);
+                }
+                forward_message(to)?;
+                Ok(true)
+            };
+            let forwarded = forward();
+            if let Some(seq) = seq {
+                let nack = |e: &SignallerError| SignallerMessage::Nack {
+                    seq,
+                    reason: e.to_string(),
+                    code: e.code().to_string(),
+                };
+                let reply = match &forwarded {
+                    Ok(true) => SignallerMessage::Ack { seq },
+                    // Dropped by the rate limit, which doesn't otherwise tell the sender.
+                    Ok(false) => nack(&SignallerError::RateLimited),
+                    Err(e) => nack(e),
+                };
+                send_message(tx, &reply);
+            }
+            forwarded?;
+        }
+        SignallerMessage::KeepAlive {}
+        | SignallerMessage::RoomClosed { .. }
+        | SignallerMessage::JoinDeclined { .. }
+        | SignallerMessage::AppPong { .. }
+        | SignallerMessage::JoinPending { .. }
+        | SignallerMessage::Retry { .. }
+        | SignallerMessage::Ack { .. }
+        | SignallerMessage::Nack { .. }
+        | SignallerMessage::Identity { .. }
+        | SignallerMessage::StartResponse { .. }
+        | SignallerMessage::Resumed { .. }
+        | SignallerMessage::IceServersResponse { .. }
+        | SignallerMessage::Error { .. }
+        | SignallerMessage::AdminStats { .. }
+        | SignallerMessage::RoomDraining { .. }
+        | SignallerMessage::RoomList { .. }
+        | SignallerMessage::SessionList { .. }
+        | SignallerMessage::ViewerCount { .. }
+        | SignallerMessage::ServerShutdown { .. }
+        | SignallerMessage::ViewerList { .. }
+        | SignallerMessage::RoomStatsResponse { .. }
+        | SignallerMessage::Kicked {}
+        | SignallerMessage::BroadcastFrom { .. }
+        | SignallerMessage::RenegotiateRequest {}
+        | SignallerMessage::QualityRequestFrom { .. }
+        | SignallerMessage::SessionTimedOut {}
+        | SignallerMessage::SessionExpiring { .. }
+        | SignallerMessage::SessionExpired {}
+        | SignallerMessage::PeerJoined { .. }
+        | SignallerMessage::AdmissionRequest { .. }
+        | SignallerMessage::Admitted {}
+        | SignallerMessage::SharerChanged { .. }
+        | SignallerMessage::SharerPromoted { .. }
+        | SignallerMessage::Presence { .. } => {}
+    };
+    Ok(())
+}
+
+/// Counts a live websocket connection for as long as it is held, including through a panic.
+struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl ConnectionGuard {
+    /// Count a new connection, or return `None` if `max` connections are already open.
+    fn acquire(count: &Arc<AtomicUsize>, max: Option<usize>) -> Option<ConnectionGuard> {
+        let open = count.fetch_add(1, Ordering::SeqCst);
+        metrics::NUM_CONNECTIONS.inc();
+        let guard = ConnectionGuard(count.clone());
+        if max.is_some_and(|max| open >= max) {
+            return None;
+        }
+        Some(guard)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+        metrics::NUM_CONNECTIONS.dec();
+    }
+}
+
+/// Query parameters a client may pass when opening the websocket.
+#[derive(Deserialize)]
+struct ConnectQuery {
+    /// `msgpack` to exchange MessagePack in binary frames instead of JSON.
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+/// State owned by a single websocket connection.
+struct Connection {
+    /// Key of this connection in `State::connections`.
+    id: u64,
+    tx: Tx,
+    /// The peer id this connection registered with a `Start` or `Join`, if any.
+    peer_id: Mutex<Option<String>>,
+    last_pong: Mutex<Instant>,
+    /// Limits the messages this connection has forwarded to other peers.
+    limiter: Mutex<MessageLimiter>,
+    access_log: AccessLog,
+}
+
+/// The kind of frame `msg` is, as labelled in `IGNORED_FRAMES`.
+fn frame_kind(msg: &Message) -> &'static str {
+    if msg.is_text() {
+        "text"
+    } else if msg.is_binary() {
+        "binary"
+    } else if msg.is_ping() {
+        "ping"
+    } else if msg.is_close() {
+        "close"
+    } else {
+        "other"
+    }
+}
+
+async fn process_message(
+    msg: Message,
+    state: StateType,
+    connection: &Connection,
+) -> std::result::Result<(), warp::Error> {
+    if msg.is_pong() {
+        *connection.last_pong.lock().await = Instant::now();
+        return Ok(());
+    }
+    let tx = &connection.tx;
+    if !tx.encoding.accepts(&msg) {
+        let kind = frame_kind(&msg);
+        metrics::IGNORED_FRAMES.with_label_values(&[kind]).inc();
+        // Pings and close frames are answered by the websocket layer, anything else is the client
+        // using the wrong frame type for its encoding.
+        if msg.is_text() || msg.is_binary() {
+            debug!(
+                "Ignoring {} frame ({} bytes) on a {:?} connection",
+                kind,
+                msg.as_bytes().len(),
+                tx.encoding
+            );
+        }
+        return Ok(());
+    }
+
+    let mut peer_id = connection.peer_id.lock().await;
+    if peer_id
+        .as_deref()
+        .is_some_and(|id| !state.registered_as(id, tx))
+    {
+        *peer_id = None;
+    }
+    // Once the connection has registered, attribute everything it does to its peer and room.
+    let span = match peer_id.as_deref() {
+        Some(id) => {
+            let room = state.peers.get(id).map(|peer| peer.room.clone());
+            info_span!("peer", id, room = room.as_deref().unwrap_or_default())
+        }
+        None => Span::none(),
+    };
+    let mut limiter = connection.limiter.lock().await;
+    let handled = handle_message(&state, tx, &msg, &mut peer_id, connection.id, &mut limiter)
+        .instrument(span.clone())
+        .await;
+    state
+        .connections
+        .set_peer(connection.id, peer_id.as_deref());
+    let _enter = span.enter();
+    if connection.access_log == AccessLog::Json {
+        let room = peer_id
+            .as_deref()
+            .and_then(|id| state.peers.get(id).map(|peer| peer.room.clone()));
+        AccessLogEntry {
+            peer: peer_id.as_deref(),
+            room: room.as_deref(),
+            kind: tx.encoding.message_type(&msg).as_deref(),
+            bytes: msg.as_bytes().len(),
+            ok: handled.is_ok(),
+        }
+        .write();
+    }
+    if let Err(e) = handled {
+        metrics::MESSAGE_ERRORS.inc();
+        // Payloads carry SDP and ICE candidates, i.e. private addresses, so only log them at debug.
+        info!(
+            "Error handling {} message ({} bytes): {}",
+            tx.encoding
+                .message_type(&msg)
+                .as_deref()
+                .unwrap_or("unknown"),
+            msg.as_bytes().len(),
+            e
+        );
+        debug!("Message: {}", String::from_utf8_lossy(msg.as_bytes()));
+        send_message(
+            tx,
+            &SignallerMessage::Error {
+                message: e.to_string(),
+                code: e.code().to_string(),
+            },
+        );
+    }
+    Ok(())
+}
+
+async fn handle_connection(
+    args: Args,
+    state: StateType,
+    websocket: WebSocket,
+    socket_addr: SocketAddr,
+    real_ip: Option<&IpAddr>,
+    encoding: Encoding,
+) {
+    let hashed_ip = real_ip
+        .and_then(|real_ip| {
+            metrics::hash_ip(real_ip, &args.ip_hash_salt)
+                .map_err(|e| warn!("Error hashing IP of {socket_addr}: {}", e))
+                .ok()
+        })
+        .unwrap_or("unknown".to_string());
+
+    metrics::NUM_CONNECTED_CLIENTS
+        .with_label_values(&[hashed_ip.as_str()])
+        .inc();
+
+    // Insert the write part of this peer to the peer map.
+    let (tx, mut rx) = mpsc::channel(args.send_queue_size as usize);
+    let (mut outgoing, incoming) = websocket.split();
+    let (id, close_requested) = state.connections.open(socket_addr);
+    let connection = Connection {
+        id,
+        tx: Tx::new(tx, encoding),
+        peer_id: Mutex::new(None),
+        last_pong: Mutex::new(Instant::now()),
+        limiter: Mutex::new(MessageLimiter::new(args.message_rate, args.message_burst)),
+        access_log: args.access_log,
+    };
+
+    info!(
+        "WebSocket connection established: {socket_addr}, real IP: {:?}",
+        real_ip
+    );
+
+    // Messages are handled one at a time in the order they arrive, and each peer has a single
+    // queue, so whatever one peer sends another arrives in order. ICE and SDP depend on that.
+    let handle_incoming =
+        incoming.try_for_each(|msg| process_message(msg, state.clone(), &connection));
+
+    let write_timeout = Duration::from_secs(args.write_timeout);
+    let receive_from_others = async move {
+        while let Some(msg) = rx.recv().await {
+            match tokio::time::timeout(write_timeout, outgoing.send(msg)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    info!("Error writing to {socket_addr}: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("{socket_addr} stopped reading its messages, dropping it");
+                    return;
+                }
+            }
+        }
+    };
+
+    let ping_interval = Duration::from_secs(args.ping_interval);
+    let heartbeat = async {
+        let mut interval = tokio::time::interval(ping_interval);
+        loop {
+            interval.tick().await;
+            if connection.last_pong.lock().await.elapsed() > ping_interval * 2 {
+                info!("{socket_addr} stopped answering pings");
+                return;
+            }
+            connection
+                .tx
+                .send(Message::ping(Vec::new()))
+                .unwrap_or_else(|e| {
+                    info!("Error sending ping: {}", e);
+                });
+        }
+    };
+
+    tokio::pin!(receive_from_others);
+    let close = tokio::select! {
+        result = handle_incoming => match result {
+            // Oversized or malformed frames end the incoming stream with an error.
+            Err(e) => {
+                info!("Closing {socket_addr} after websocket error: {}", e);
+                Some((CLOSE_POLICY_VIOLATION, "protocol error"))
+            }
+            Ok(()) => None,
+        },
+        _ = &mut receive_from_others => None,
+        _ = heartbeat => Some((CLOSE_PING_TIMEOUT, "ping timeout")),
+        _ = close_requested.notified() => Some((CLOSE_REGISTER_TIMEOUT, "register timeout")),
+        _ = connection.tx.overflowed() => {
+            // The queue is full, so there is no room left for a close frame either.
+            warn!("{socket_addr} is not keeping up with its messages, dropping it");
+            None
+        }
+    };
+    if let Some((code, reason)) = close {
+        let _ = connection.tx.send(Message::close_with(code, reason));
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, &mut receive_from_others).await;
+    }
+
+    metrics::NUM_CONNECTED_CLIENTS
+        .with_label_values(&[hashed_ip.as_str()])
+        .dec();
+    state.connections.close(connection.id);
+
+    info!("{socket_addr} disconnected, real IP: {:?}", real_ip);
+    let peer_id = connection.peer_id.lock().await.take();
+    // Leave the id alone if the server already dropped this peer and someone else has it now.
+    if let Some(id) = peer_id.filter(|id| state.registered_as(id, &connection.tx)) {
+        let disconnected = state.disconnect_peer(&id);
+        match disconnected {
+            Ok(Some(room)) => {
+                let grace = Duration::from_secs(args.resume_grace);
+                tokio::spawn(async move {
+                    tokio::time::sleep(grace).await;
+                    if let Err(e) = state.expire_disconnected_sharer(&room) {
+                        info!("Error expiring room {}: {}", room, e);
+                    }
+                });
+            }
+            Ok(None) => {}
+            Err(e) => info!("Error cleaning up {}: {}", id, e),
+        }
+    }
+}
+
+/// A `Retry` asking the client to come back after a jittered delay.
+fn retry(state: &StateType, reason: String) -> SignallerMessage {
+    SignallerMessage::Retry {
+        after_ms: state.reconnect_after_ms(),
+        reason,
+    }
+}
+
+/// Tell a client the server is full and when to try again, then hang up.
+async fn turn_away(mut websocket: WebSocket, state: StateType, encoding: Encoding) {
+    let reason = "server is full";
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, async {
+        websocket
+            .send(encoding.encode(&retry(&state, reason.to_string())))
+            .await?;
+        websocket
+            .send(Message::close_with(CLOSE_TRY_AGAIN_LATER, reason))
+            .await
+    })
+    .await;
+}
+
+/// Periodically closes rooms that have outlived their limits.
+async fn reap_sessions(state: StateType) {
+    let mut interval = tokio::time::interval(REAPER_INTERVAL);
+    loop {
+        interval.tick().await;
+        for state in state.with_namespaces() {
+            state.reap_sessions();
+            state.reap_waiting();
+        }
+        if let Some(timeout) = state.register_timeout {
+            state.connections.reap_unregistered(timeout);
+        }
+    }
+}
+
+/// Periodically sends stats to admin subscribers, with the message rate measured over each
+/// interval.
+async fn push_admin_stats(state: StateType) {
+    let mut interval = tokio::time::interval(ADMIN_STATS_INTERVAL);
+    let mut last = (Instant::now(), metrics::MESSAGES_FORWARDED.get());
+    loop {
+        interval.tick().await;
+        let now = (Instant::now(), metrics::MESSAGES_FORWARDED.get());
+        let elapsed = now.0.duration_since(last.0).as_secs_f64();
+        let messages_per_sec = if elapsed > 0.0 {
+            now.1.saturating_sub(last.1) as f64 / elapsed
+        } else {
+            0.0
+        };
+        last = now;
+        state.push_admin_stats(messages_per_sec);
+    }
+}
+
+/// Whether a websocket upgrade from `origin` may proceed. Requests without an `Origin` header
+/// don't come from a browser page, so there is no other site to protect against.
+fn origin_allowed(allowed: &[String], origin: Option<&str>) -> bool {
+    let Some(origin) = origin else {
+        return true;
+    };
+    allowed
+        .iter()
+        .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+}
+
+async fn prune_rate_limiter(limiter: Arc<RateLimiter>) {
+    let mut interval = tokio::time::interval(RATE_LIMIT_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        limiter.prune();
+    }
+}
+
+/// Resolves once the process is asked to stop with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// The signalling server, ready to accept connections.
+pub struct Server {
+    args: Args,
+    state: StateType,
+}
+
+/// The peer address of a connection accepted by `Server::run`, which warp can't see itself.
+#[derive(Clone, Copy)]
+struct RemoteAddr(SocketAddr);
+
+impl Server {
+    pub fn new(args: Args, config: Config) -> Server {
+        metrics::register();
+        let state = state::State::new(&config, &args);
+        Server { args, state }
+    }
+
+    /// Listen on every `--bind` address, with TLS if `--cert` and `--key` are given, until
+    /// SIGINT or SIGTERM. Client certificates are required if `--tls-client-ca` is also given.
+    pub async fn serve(self) -> anyhow::Result<()> {
+        let Some((cert, key)) = self.args.cert.clone().zip(self.args.key.clone()) else {
+            let listeners = self
+                .args
+                .bind
+                .iter()
+                .map(|&addr| bind_listener(addr, self.args.reuse_port))
+                .collect::<std::io::Result<_>>()?;
+            return self.run_listeners(listeners).await;
+        };
+        let routes = self.routes();
+        let servers = self
+            .args
+            .bind
+            .iter()
+            .map(|&addr| {
+                let server = warp::serve(routes.clone())
+                    .tls()
+                    .cert_path(&cert)
+                    .key_path(&key);
+                let server = match &self.args.tls_client_ca {
+                    Some(ca) => server.client_auth_required_path(ca),
+                    None => server,
+                };
+                // The certificate and key are only read here, so this is where a bad one shows.
+                // Failing on any address stops them all, rather than serving on just some.
+                let (addr, server) = server
+                    .try_bind_with_graceful_shutdown(addr, shutdown_signal())
+                    .with_context(|| {
+                        let mut files =
+                            format!("--cert {} and --key {}", cert.display(), key.display());
+                        if let Some(ca) = &self.args.tls_client_ca {
+                            files += &format!(" and --tls-client-ca {}", ca.display());
+                        }
+                        format!("serving TLS on {addr} with {files}")
+                    })?;
+                info!("Server listening on {} (TLS)", addr);
+                anyhow::Ok(server)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        future::join_all(servers).await;
+        self.shut_down().await;
+        Ok(())
+    }
+
+    /// Accept plain websocket connections from `listener` until SIGINT or SIGTERM. TLS needs
+    /// `serve`, which binds its own listeners.
+    pub async fn run(self, listener: TcpListener) -> anyhow::Result<()> {
+        self.run_listeners(vec![listener]).await
+    }
+
+    async fn run_listeners(self, listeners: Vec<TcpListener>) -> anyhow::Result<()> {
+        if self.args.cert.is_some() || self.args.key.is_some() {
+            anyhow::bail!("TLS is only supported by Server::serve");
+        }
+        let service = warp::service(self.routes());
+        let permits = Arc::new(Semaphore::new(
+            self.args.max_accepted.unwrap_or(Semaphore::MAX_PERMITS),
+        ));
+        let servers = listeners.into_iter().map(|listener| {
+            let service = service.clone();
+            let permits = permits.clone();
+            async move {
+                info!("Server listening on {}", listener.local_addr()?);
+                let make_service = make_service_fn(move |conn: &LimitedStream| {
+                    let service = service.clone();
+                    let remote_addr = RemoteAddr(conn.remote_addr());
+                    async move {
+                        Ok::<_, Infallible>(service_fn(move |mut req| {
+                            req.extensions_mut().insert(remote_addr);
+                            service.clone().call(req)
+                        }))
+                    }
+                });
+                let mut incoming = AddrIncoming::from_listener(listener)?;
+                incoming.set_nodelay(true);
+                hyper::Server::builder(LimitedIncoming::new(incoming, permits))
+                    .serve(make_service)
+                    .with_graceful_shutdown(shutdown_signal())
+                    .await?;
+                anyhow::Ok(())
+            }
+        });
+        future::try_join_all(servers).await?;
+        self.shut_down().await;
+        Ok(())
+    }
+
+    /// Start the background tasks and side servers, and build the public routes.
+    fn routes(&self) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let args = self.args.clone();
+        let state = self.state.clone();
+        tokio::spawn(reap_sessions(state.clone()));
+        if state.admin_token.is_some() {
+            tokio::spawn(push_admin_stats(state.clone()));
+        }
+        let limiter = Arc::new(RateLimiter::new(
+            args.connection_rate,
+            args.connection_burst,
+        ));
+        tokio::spawn(prune_rate_limiter(limiter.clone()));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let max_connections = args.max_connections;
+
+        let max_message_size = args.max_message_size;
+        let max_unregistered = args.max_unregistered;
+        let scheme = if args.cert.is_some() { "wss" } else { "ws" };
+        let server_header = Some(args.server_header.as_str())
+            .filter(|value| !value.is_empty())
+            .map(|value| HeaderValue::from_str(value).expect("checked when parsing arguments"));
+
+        use warp::{any, ws};
+        let metrics_state = state.clone();
+        let metrics_route = warp::path!("metrics")
+            .and(any().map(move || metrics_state.clone()))
+            .and_then(metrics::metrics_handler);
+        let metrics_addr = args.metrics_addr;
+        if let Some(metrics_addr) = metrics_addr {
+            info!("Metrics listening on {}", metrics_addr);
+            tokio::spawn(warp::serve(metrics_route.clone()).run(metrics_addr));
+        }
+        if let Some(health_addr) = args.health_addr {
+            let health_state = state.clone();
+            let health_route = warp::path!("healthz").and(warp::get()).map(move || {
+                warp::reply::json(&serde_json::json!({
+                    "status": "ok",
+                    "sessions": health_state
+                        .with_namespaces()
+                        .map(|state| state.sessions.len())
+                        .sum::<usize>(),
+                }))
+            });
+            info!("Health check listening on {}", health_addr);
+            tokio::spawn(warp::serve(health_route).run(health_addr));
+        }
+        // Metrics stay off the public port when they have an address of their own.
+        let metrics_route = any()
+            .and_then(move || async move {
+                match metrics_addr {
+                    None => Ok(()),
+                    Some(_) => Err(warp::reject::not_found()),
+                }
+            })
+            .untuple_one()
+            .and(metrics_route);
+        // The default namespace is served on /, the others on /ns/<name>.
+        let namespace = warp::path::end()
+            .map(|| None)
+            .or(warp::path!("ns" / String).map(Some))
+            .unify();
+        let ws_route = namespace
+            .and(ws().map(move |ws: ws::Ws| {
+                ws.max_message_size(max_message_size)
+                    .max_frame_size(max_message_size)
+            }))
+            .and(remote_addr())
+            .and(warp_real_ip::get_forwarded_for())
+            .and(warp::header::optional::<String>("origin"))
+            .and(warp::query::<ConnectQuery>())
+            .and(warp::header::optional::<String>("sec-websocket-protocol"))
+            .and(any().map(move || args.clone()))
+            .and(any().map(move || state.clone()))
+            .map(
+                move |namespace: Option<String>,
+                      ws: ws::Ws,
+                      socket_addr: Option<SocketAddr>,
+                      real_ip_addrs: Vec<IpAddr>,
+                      origin: Option<String>,
+                      query: ConnectQuery,
+                      requested_protocols: Option<String>,
+                      args: Args,
+                      state: StateType| {
+                    let state = match namespace.as_deref().map(|name| state.namespace(name)) {
+                        None => state,
+                        Some(Some(state)) => state.clone(),
+                        Some(None) => return StatusCode::NOT_FOUND.into_response(),
+                    };
+                    if !origin_allowed(&args.allowed_origins, origin.as_deref()) {
+                        info!("Rejecting connection from origin {:?}", origin);
+                        return StatusCode::FORBIDDEN.into_response();
+                    }
+                    // Clients that don't ask for a subprotocol get the original schema.
+                    let protocol = match requested_protocols.as_deref().map(Protocol::negotiate) {
+                        None => None,
+                        Some(Some(protocol)) => Some(protocol),
+                        Some(None) => {
+                            info!(
+                                "Rejecting connection asking for unsupported protocols {:?}",
+                                requested_protocols
+                            );
+                            return warp::reply::with_status(
+                                "unsupported signalling protocol",
+                                StatusCode::BAD_REQUEST,
+                            )
+                            .into_response();
+                        }
+                    };
+                    // Behind a proxy every socket shares its address, so prefer the forwarded one,
+                    // but only from a proxy. Anyone else could make up a new one each time.
+                    let forwarded_ip = socket_addr.and_then(|addr| {
+                        forwarded_client(addr.ip(), &real_ip_addrs, &args.trusted_proxies)
+                    });
+                    let client_ip = forwarded_ip.or(socket_addr.map(|addr| addr.ip()));
+                    if let Some(ip) = client_ip {
+                        if !limiter.check(ip) {
+                            info!("Rejecting connection from {ip}: rate limited");
+                            return StatusCode::TOO_MANY_REQUESTS.into_response();
+                        }
+                    }
+                    // Connections that sit idle after the handshake must not be able to pile up.
+                    if max_unregistered.is_some_and(|max| state.connections.unregistered() >= max) {
+                        info!(
+                            "Rejecting connection from {:?}: too many unregistered connections",
+                            client_ip
+                        );
+                        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+                    }
+                    let encoding = query.encoding;
+                    let reply = match ConnectionGuard::acquire(&connections, max_connections) {
+                        Some(connection_guard) => ws
+                            .on_upgrade(move |socket| async move {
+                                let _connection_guard = connection_guard;
+                                let Some(socket_addr) = socket_addr else {
+                                    warn!("Dropping websocket connection without a remote address");
+                                    return;
+                                };
+                                handle_connection(
+                                    args,
+                                    state,
+                                    socket,
+                                    socket_addr,
+                                    client_ip.as_ref(),
+                                    encoding,
+                                )
+                                .instrument(info_span!("connection", addr = %socket_addr))
+                                .await
+                            })
+                            .into_response(),
+                        None => {
+                            info!(
+                                "Rejecting connection from {:?}: too many connections",
+                                client_ip
+                            );
+                            ws.on_upgrade(move |socket| turn_away(socket, state, encoding))
+                                .into_response()
+                        }
+                    };
+                    match protocol {
+                        Some(protocol) => warp::reply::with_header(
+                            reply,
+                            "sec-websocket-protocol",
+                            protocol.name(),
+                        )
+                        .into_response(),
+                        None => reply.into_response(),
+                    }
+                },
+            )
+            .map(move |mut response: warp::reply::Response| {
+                if let Some(server_header) = &server_header {
+                    response.headers_mut().insert(SERVER, server_header.clone());
+                }
+                response
+            });
+
+        // Browsers and HTTP health checks get an explanation instead of a failed upgrade.
+        let landing_route = warp::path::end()
+            .and(warp::get())
+            .and(warp::header::optional::<String>("host"))
+            .map(move |host: Option<String>| {
+                let host = host.as_deref().unwrap_or("<host>");
+                let body = format!(
+                    "This is a WebRTC signalling server, it only speaks websockets.\n\
+                     Connect with a websocket client, e.g. new WebSocket(\"{scheme}://{host}/\"), \
+                     optionally with ?encoding=msgpack and the signaller.v1 subprotocol.\n"
+                );
+                let reply = warp::reply::with_status(body, StatusCode::UPGRADE_REQUIRED);
+                warp::reply::with_header(reply, "upgrade", "websocket")
+            });
+
+        metrics_route.or(ws_route).or(landing_route)
+    }
+
+    /// Tell every peer the server is going away and give them a moment to hear it.
+    async fn shut_down(&self) {
+        info!("Shutting down");
+        self.state.shutdown();
+        tokio::time::sleep(SHUTDOWN_GRACE_PERIOD).await;
+    }
+}
+
+/// Bind a listener for `addr`. IPv6 listeners don't take IPv4 connections, so `0.0.0.0` and
+/// `[::]` can both be bound on the same port. With `reuse_port` other processes may bind it too.
+fn bind_listener(addr: SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // Lets a restarted server bind while old connections linger in TIME_WAIT. On Windows it would
+    // let another process take the port from under us instead.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        #[cfg(not(unix))]
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "--reuse-port is only supported on Unix",
+        ));
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// The client's socket address, whether the connection was accepted by warp or `Server::run`.
+fn remote_addr() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<RemoteAddr>())
+        .map(|addr: Option<SocketAddr>, accepted: Option<RemoteAddr>| {
+            addr.or(accepted.map(|RemoteAddr(addr)| addr))
+        })
+}
+
+/// Log to stderr at `--log-level`, unless `RUST_LOG` says otherwise. Stdout is left to the
+/// access log.
+pub fn init_tracing(args: &Args) {
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&args.log_level)),
+        )
+        .init();
+}
@@ -1,10 +1,16 @@
 use std::net::IpAddr;
+use std::sync::Once;
 
 use lazy_static::lazy_static;
-use log::error;
-use prometheus::{Histogram, HistogramOpts, IntGauge, IntGaugeVec, Opts, Registry};
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
+use tracing::error;
 use warp::{Rejection, Reply};
 
+use crate::peer::PeerType;
+use crate::state::StateType;
+
 lazy_static! {
     static ref REGISTRY: Registry = Registry::new();
     pub static ref NUM_CONNECTED_CLIENTS: IntGaugeVec = IntGaugeVec::new(
@@ -12,8 +18,69 @@ lazy_static! {
         &["hashed_ip"]
     )
     .expect("metric can be created");
+    pub static ref NUM_CONNECTIONS: IntGauge =
+        IntGauge::new("num_connections", "Open Websocket Connections")
+            .expect("metric can be created");
+    pub static ref ACCEPTED_CONNECTIONS: IntGauge = IntGauge::new(
+        "accepted_connections",
+        "Accepted TCP Connections Still Open"
+    )
+    .expect("metric can be created");
+    pub static ref NUM_UNREGISTERED_CONNECTIONS: IntGauge = IntGauge::new(
+        "num_unregistered_connections",
+        "Open Websocket Connections Without A Room"
+    )
+    .expect("metric can be created");
     pub static ref NUM_ONGOING_SESSIONS: IntGauge =
         IntGauge::new("num_ongoing_sessions", "Ongoing Sessions").expect("metric can be created");
+    pub static ref NUM_PEERS: IntGaugeVec =
+        IntGaugeVec::new(Opts::new("num_peers", "Registered Peers"), &["peer_type"])
+            .expect("metric can be created");
+    pub static ref MESSAGES_FORWARDED: IntCounter =
+        IntCounter::new("messages_forwarded", "Messages Forwarded").expect("metric can be created");
+    pub static ref MESSAGE_ERRORS: IntCounter =
+        IntCounter::new("message_errors", "Message Handling Errors")
+            .expect("metric can be created");
+    pub static ref MESSAGES_RATE_LIMITED: IntCounter = IntCounter::new(
+        "messages_rate_limited",
+        "Messages Dropped For Exceeding The Message Rate"
+    )
+    .expect("metric can be created");
+    pub static ref IGNORED_FRAMES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "ignored_frames",
+            "Frames Ignored For Not Carrying A Message"
+        ),
+        &["kind"]
+    )
+    .expect("metric can be created");
+    pub static ref UNKNOWN_MESSAGES: IntCounter =
+        IntCounter::new("unknown_messages", "Messages With An Unknown Type")
+            .expect("metric can be created");
+    pub static ref SEND_QUEUE_DEPTH: Histogram = Histogram::with_opts(
+        HistogramOpts::new(
+            "send_queue_depth",
+            "Messages Queued For A Peer, Including The One Just Queued"
+        )
+        .buckets(vec![
+            1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0, 1024.0, 2048.0, 4096.0
+        ])
+    )
+    .expect("metric can be created");
+    pub static ref MAX_SEND_QUEUE_DEPTH: IntGauge = IntGauge::new(
+        "max_send_queue_depth",
+        "Most Messages Queued For Any One Peer"
+    )
+    .expect("metric can be created");
+    pub static ref SESSIONS_ENDED: IntCounterVec =
+        IntCounterVec::new(Opts::new("sessions_ended", "Sessions Ended"), &["reason"])
+            .expect("metric can be created");
+    pub static ref SESSION_PEAK_VIEWERS: Histogram = Histogram::with_opts(
+        HistogramOpts::new("session_peak_viewers", "Most Viewers In A Session At Once").buckets(
+            vec![0.0, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0]
+        )
+    )
+    .expect("metric can be created");
     pub static ref SESSION_DURATION_SEC: Histogram = Histogram::with_opts(
         HistogramOpts::new("session_duration_sec", "Session Duration Seconds").buckets(vec![
             1.0, 5.0, 10.0, 15.0, 20.0, 25.0, 30.0, 40.0, 50.0, 60.0, 90.0, 120.0, 180.0, 240.0,
@@ -24,19 +91,81 @@ lazy_static! {
 }
 
 pub(crate) fn register() {
+    // A process can run more than one server, e.g. in the integration tests.
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(register_collectors);
+}
+
+fn register_collectors() {
     REGISTRY
         .register(Box::new(NUM_CONNECTED_CLIENTS.clone()))
         .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_CONNECTIONS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(ACCEPTED_CONNECTIONS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_UNREGISTERED_CONNECTIONS.clone()))
+        .expect("collector can be registered");
     REGISTRY
         .register(Box::new(NUM_ONGOING_SESSIONS.clone()))
         .expect("collector can be registered");
     REGISTRY
         .register(Box::new(SESSION_DURATION_SEC.clone()))
         .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(SEND_QUEUE_DEPTH.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MAX_SEND_QUEUE_DEPTH.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(SESSIONS_ENDED.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(SESSION_PEAK_VIEWERS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(NUM_PEERS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MESSAGES_FORWARDED.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MESSAGE_ERRORS.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(MESSAGES_RATE_LIMITED.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(IGNORED_FRAMES.clone()))
+        .expect("collector can be registered");
+    REGISTRY
+        .register(Box::new(UNKNOWN_MESSAGES.clone()))
+        .expect("collector can be registered");
 }
 
-pub(crate) async fn metrics_handler() -> Result<impl Reply, Rejection> {
+pub(crate) async fn metrics_handler(state: StateType) -> Result<impl Reply, Rejection> {
     use prometheus::Encoder;
+
+    {
+        let peers = || state.with_namespaces().flat_map(|state| state.peers.iter());
+        let sharers = peers()
+            .filter(|peer| matches!(peer.peer_type, PeerType::Sharer {}))
+            .count();
+        let viewers = peers().count() - sharers;
+        NUM_PEERS.with_label_values(&["sharer"]).set(sharers as i64);
+        NUM_PEERS.with_label_values(&["viewer"]).set(viewers as i64);
+        NUM_UNREGISTERED_CONNECTIONS.set(state.connections.unregistered() as i64);
+        let max_depth = peers()
+            .map(|peer| peer.sender.queue_depth())
+            .max()
+            .unwrap_or(0);
+        MAX_SEND_QUEUE_DEPTH.set(max_depth as i64);
+    }
+
     let encoder = prometheus::TextEncoder::new();
 
     let mut buffer = Vec::new();
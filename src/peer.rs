@@ -1,18 +1,114 @@
-use futures_channel::mpsc::UnboundedSender;
+use std::sync::Arc;
+use std::time::Instant;
+
 use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tracing::info;
 use warp::ws::Message;
 
-type Tx = UnboundedSender<Message>;
+use crate::metrics;
+use crate::signaller_message::{Encoding, SignallerMessage};
+
+/// Close code sent when a client breaks the protocol, e.g. by sending an oversized message.
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+/// Close code sent when the server is going away and the client should reconnect later.
+pub const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+/// Close code sent to a viewer the sharer kicked out of the room.
+pub const CLOSE_KICKED: u16 = 4000;
+/// Close code sent when a client stops answering pings.
+pub const CLOSE_PING_TIMEOUT: u16 = 4001;
+/// Close code sent when a client doesn't start or join a room within `--register-timeout`.
+pub const CLOSE_REGISTER_TIMEOUT: u16 = 4002;
+/// Close code sent when a client gets room passwords wrong too many times.
+pub const CLOSE_PASSWORD_FAILURES: u16 = 4003;
+
+/// The write half of a peer's connection, along with the encoding the peer speaks.
+#[derive(Clone)]
+pub struct Tx {
+    sender: Sender<Message>,
+    pub encoding: Encoding,
+    overflowed: Arc<Notify>,
+}
+
+impl Tx {
+    pub fn new(sender: Sender<Message>, encoding: Encoding) -> Tx {
+        Tx {
+            sender,
+            encoding,
+            overflowed: Default::default(),
+        }
+    }
+
+    /// Queue a message without waiting. A full queue means the peer isn't keeping up, which
+    /// wakes `overflowed` so the connection can be dropped.
+    pub fn send(&self, msg: Message) -> Result<(), TrySendError<Message>> {
+        self.sender.try_send(msg).map_err(|e| {
+            if let TrySendError::Full(_) = e {
+                self.overflowed.notify_one();
+            }
+            e
+        })?;
+        metrics::SEND_QUEUE_DEPTH.observe(self.queue_depth() as f64);
+        Ok(())
+    }
+
+    /// Whether both send to the same connection.
+    pub fn same_connection(&self, other: &Tx) -> bool {
+        self.sender.same_channel(&other.sender)
+    }
+
+    /// How many messages are queued and not yet written to the connection.
+    pub fn queue_depth(&self) -> usize {
+        self.sender.max_capacity() - self.sender.capacity()
+    }
+
+    /// Whether the connection this sends to has gone away.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Resolves once a message could not be queued because the queue was full.
+    pub async fn overflowed(&self) {
+        self.overflowed.notified().await
+    }
+}
 
 pub struct Peer {
     pub room: String,
     pub sender: Tx,
     pub peer_type: PeerType,
+    /// When the peer's current connection registered, reset when a sharer resumes.
+    pub connected_at: Instant,
+}
+
+impl Peer {
+    /// Whole seconds since `connected_at`.
+    pub fn connected_secs(&self) -> u64 {
+        self.connected_at.elapsed().as_secs()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Format a number of seconds as `HH:MM:SS`.
+pub fn format_uptime(secs: u64) -> String {
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum PeerType {
     Sharer {},
     Viewer {},
+    /// A viewer that only receives, it may not send offers, answers or ICE candidates.
+    Spectator {},
+}
+
+/// Queue a message for a peer, logging instead of failing if the peer has gone away.
+pub fn send_message(sender: &Tx, msg: &SignallerMessage) {
+    sender
+        .send(sender.encoding.encode(msg))
+        .unwrap_or_else(|e| {
+            info!("Error sending message: {}", e);
+        });
 }
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use tracing::warn;
+
+/// Token bucket per client IP, refilled at `rate` tokens a second up to `burst`.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+/// Token bucket for the messages a single connection asks the server to forward, refilled at
+/// `rate` tokens a second up to `burst`.
+pub struct MessageLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Bucket,
+    /// Messages dropped since the last one that was let through.
+    dropped: u32,
+}
+
+impl MessageLimiter {
+    pub fn new(rate: f64, burst: u32) -> MessageLimiter {
+        MessageLimiter {
+            rate,
+            burst: burst as f64,
+            bucket: Bucket {
+                tokens: burst as f64,
+                updated: Instant::now(),
+            },
+            dropped: 0,
+        }
+    }
+
+    /// Take a token, returning false if there are none left.
+    pub fn check(&mut self) -> bool {
+        self.bucket.refill(Instant::now(), self.rate, self.burst);
+        if self.bucket.tokens < 1.0 {
+            self.dropped += 1;
+            return false;
+        }
+        self.bucket.tokens -= 1.0;
+        self.dropped = 0;
+        true
+    }
+
+    /// How many messages in a row `check` has refused.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token for `ip`, returning false if it has none left.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.lock_buckets();
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.refill(now, self.rate, self.burst);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Forget IPs whose bucket has filled back up, since a new bucket would look the same.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let (rate, burst) = (self.rate, self.burst);
+        self.lock_buckets().retain(|_, bucket| {
+            bucket.refill(now, rate, burst);
+            bucket.tokens < burst
+        });
+    }
+
+    /// Lock the buckets, carrying on with them if a panic poisoned the lock. At worst a bucket
+    /// holds a stale count, which isn't worth refusing every connection over.
+    fn lock_buckets(&self) -> MutexGuard<'_, HashMap<IpAddr, Bucket>> {
+        self.buckets.lock().unwrap_or_else(|e| {
+            warn!("Rate limiter lock was poisoned, recovering it");
+            e.into_inner()
+        })
+    }
+}
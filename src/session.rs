@@ -1,21 +1,132 @@
-use std::collections::HashSet;
-use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::atomic::AtomicU64;
+use std::time::Instant;
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng, RngCore};
+
+use warp::ws::Message;
+
+use crate::signaller_message::{Encoding, SignallerMessage};
+
+const RESUME_TOKEN_LEN: usize = 32;
+/// Most messages held for a sharer whose connection dropped until it resumes.
+pub const RESUME_BUFFER_LEN: usize = 64;
 
 pub struct Session {
+    /// The sharer that owns the room and holds its resume token.
     pub sharer: String,
+    /// Peers presenting alongside `sharer`. They can't resume the room.
+    pub co_sharers: HashSet<String>,
+    /// Name viewers can join with instead of the room id.
+    pub name: Option<String>,
     pub viewers: HashSet<String>,
-    pub start_time: SystemTime,
-    pub sharer_socket_addr: SocketAddr,
+    /// Whether joining viewers wait in `pending` until a sharer admits them.
+    pub require_admission: bool,
+    /// Viewers waiting to be admitted. They are registered as peers but kept out of signalling.
+    pub pending: HashSet<String>,
+    pub started_at: Instant,
+    /// Most viewers the room has had at once.
+    pub peak_viewers: usize,
+    /// Messages forwarded to peers of this room.
+    pub messages_forwarded: AtomicU64,
+    pub max_viewers: Option<usize>,
+    /// PHC string of the room password, if the room is protected.
+    pub password_hash: Option<String>,
+    /// Lets the sharer reclaim the room from a new connection.
+    pub resume_token: String,
+    /// Set while the sharer's connection is gone but the room is kept for them to resume.
+    pub sharer_disconnected_at: Option<Instant>,
+    /// Messages forwarded to the sharer after its connection dropped, with the encoding they are
+    /// in, to be delivered when it resumes.
+    pub resume_buffer: VecDeque<(Encoding, Message)>,
+    /// When the room last became empty, `None` while it has viewers.
+    pub empty_since: Option<Instant>,
+    /// Set once the room's peers have been told it is about to reach its maximum duration.
+    pub expiry_warned: bool,
+    /// Set once the room is being torn down, from then on viewers are turned away.
+    pub closing: bool,
 }
 
 impl Session {
-    pub fn new(sharer: String, sharer_socket_addr: SocketAddr) -> Self {
+    pub fn new(
+        sharer: String,
+        max_viewers: Option<usize>,
+        password_hash: Option<String>,
+        name: Option<String>,
+        require_admission: bool,
+    ) -> Self {
         Session {
             sharer,
+            co_sharers: Default::default(),
+            name,
             viewers: Default::default(),
-            start_time: SystemTime::now(),
-            sharer_socket_addr,
+            require_admission,
+            pending: Default::default(),
+            started_at: Instant::now(),
+            peak_viewers: 0,
+            messages_forwarded: AtomicU64::new(0),
+            max_viewers,
+            password_hash,
+            resume_token: new_resume_token(),
+            sharer_disconnected_at: None,
+            resume_buffer: Default::default(),
+            empty_since: Some(Instant::now()),
+            expiry_warned: false,
+            closing: false,
         }
     }
+
+    /// The owning sharer followed by any co-sharers.
+    pub fn sharers(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.sharer).chain(&self.co_sharers)
+    }
+
+    /// Everyone in the room, sharers first.
+    pub fn members(&self) -> Vec<String> {
+        self.sharers().chain(&self.viewers).cloned().collect()
+    }
+
+    /// Snapshot of who is in the room, with how long each of them has been connected.
+    pub fn presence(&self, connected_secs: BTreeMap<String, u64>) -> SignallerMessage {
+        let sorted = |ids: &HashSet<String>| {
+            let mut ids: Vec<String> = ids.iter().cloned().collect();
+            ids.sort();
+            ids
+        };
+        SignallerMessage::Presence {
+            sharer: self.sharer.clone(),
+            co_sharers: sorted(&self.co_sharers),
+            viewers: sorted(&self.viewers),
+            connected_secs,
+        }
+    }
+}
+
+pub fn new_resume_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(RESUME_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Whether `password` matches a hash made by `hash_password`. Deliberately slow.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok()
+}
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let mut salt = [0u8; 16];
+    thread_rng().fill_bytes(&mut salt);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &SaltString::encode_b64(&salt)?)?
+        .to_string())
 }
@@ -1,43 +1,297 @@
-use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use lazy_static::lazy_static;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use tracing::info;
+use warp::ws::Message;
+
+use crate::error::{self, SignallerError};
+use crate::metrics;
+
+/// Wire format a client picked when connecting. JSON goes in text frames, MessagePack in binary.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl Encoding {
+    /// Whether `frame` is the kind of frame this encoding is carried in.
+    pub fn accepts(self, frame: &Message) -> bool {
+        match self {
+            Encoding::Json => frame.is_text(),
+            Encoding::Msgpack => frame.is_binary(),
+        }
+    }
+
+    pub fn encode(self, msg: &SignallerMessage) -> Message {
+        match self {
+            Encoding::Json => Message::text(serde_json::to_string(msg).unwrap()),
+            Encoding::Msgpack => Message::binary(rmp_serde::to_vec_named(msg).unwrap()),
+        }
+    }
+
+    /// The `type` tag of a frame, without decoding the rest of the message.
+    pub fn message_type(self, frame: &Message) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Tagged {
+            #[serde(rename = "type")]
+            kind: String,
+        }
+        let tagged: Tagged = match self {
+            Encoding::Json => serde_json::from_slice(frame.as_bytes()).ok()?,
+            Encoding::Msgpack => rmp_serde::from_slice(frame.as_bytes()).ok()?,
+        };
+        Some(tagged.kind)
+    }
+
+    pub fn decode(self, frame: &Message) -> error::Result<SignallerMessage> {
+        let value: Value = match self {
+            Encoding::Json => serde_json::from_slice(frame.as_bytes())?,
+            Encoding::Msgpack => rmp_serde::from_slice(frame.as_bytes())?,
+        };
+        let kind = value
+            .get("type")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        serde_json::from_value(value).map_err(|e| match kind {
+            Some(kind) if !MESSAGE_TYPES.contains(&kind.as_str()) => {
+                info!("Unknown message type {}", kind);
+                metrics::UNKNOWN_MESSAGES.inc();
+                SignallerError::UnknownMessageType(kind)
+            }
+            _ => e.into(),
+        })
+    }
+}
+
+/// The layers a viewer may ask for with `QualityRequest`.
+pub const QUALITY_LEVELS: [&str; 3] = ["low", "medium", "high"];
+
+lazy_static! {
+    /// Every `type` tag `SignallerMessage` accepts.
+    static ref MESSAGE_TYPES: &'static [&'static str] = message_types();
+}
+
+/// Ask serde for the tags of `SignallerMessage`, which it hands to `unknown_variant` when
+/// deserializing a tag it doesn't know.
+fn message_types() -> &'static [&'static str] {
+    #[derive(Debug)]
+    struct Variants(&'static [&'static str]);
+
+    impl std::fmt::Display for Variants {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "expected one of {:?}", self.0)
+        }
+    }
+
+    impl std::error::Error for Variants {}
+
+    impl de::Error for Variants {
+        fn custom<T: std::fmt::Display>(_: T) -> Self {
+            Variants(&[])
+        }
+
+        fn unknown_variant(_: &str, expected: &'static [&'static str]) -> Self {
+            Variants(expected)
+        }
+    }
+
+    let probe = de::value::MapDeserializer::<_, Variants>::new(std::iter::once(("type", "")));
+    match SignallerMessage::deserialize(probe) {
+        Ok(_) => &[],
+        Err(Variants(expected)) => expected,
+    }
+}
+
+/// Signalling schema versions, negotiated as websocket subprotocols so clients can pin the one
+/// they were written against. There is only one so far; a new version goes here, together with
+/// the handling that differs for it, once the schema changes in a way old clients can't follow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    V1,
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::V1 => "signaller.v1",
+        }
+    }
+
+    /// The first protocol in a `Sec-WebSocket-Protocol` header that the server speaks.
+    pub fn negotiate(requested: &str) -> Option<Protocol> {
+        requested
+            .split(',')
+            .map(str::trim)
+            .find_map(|name| match name {
+                "signaller.v1" => Some(Protocol::V1),
+                _ => None,
+            })
+    }
+}
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
 pub struct IceServer {
+    #[serde(alias = "urls")]
     pub url: String,
     #[serde(default)]
     pub username: String,
-    #[serde(default)]
+    #[serde(default, alias = "credential")]
     pub password: String,
 }
 
+/// Mirrors the browser's `RTCIceCandidateInit`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IceCandidate {
+    #[serde(deserialize_with = "non_empty_string")]
+    pub candidate: String,
+    #[serde(rename = "sdpMid", default)]
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex", default)]
+    pub sdp_mline_index: Option<u16>,
+}
+
+fn non_empty_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    if s.is_empty() {
+        return Err(de::Error::invalid_length(0, &"a non-empty string"));
+    }
+    Ok(s)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub room: String,
+    pub viewers: usize,
+}
+
+/// A live session its owner can reattach to, as listed in `SessionList`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub room: String,
+    pub name: Option<String>,
+    pub viewers: usize,
+    /// False while the sharer's connection is down and the room is waiting to be resumed.
+    pub sharer_connected: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum SignallerMessage {
+    /// Signalling between two peers of a room. A sharer may leave out `to` on any of these to send
+    /// the message to every viewer in its room.
     Offer {
-        // sdp: RTCSessionDescription,
         from: String,
-        to: String,
+        to: Option<String>,
+        /// The `RTCSessionDescription`, relayed untouched.
+        sdp: Value,
+        /// Ask the server to `Ack` or `Nack` this message under the given number.
+        seq: Option<u64>,
     },
     Answer {
-        // sdp: RTCSessionDescription,
         from: String,
-        to: String,
+        to: Option<String>,
+        /// The `RTCSessionDescription`, relayed untouched.
+        sdp: Value,
+        /// Ask the server to `Ack` or `Nack` this message under the given number.
+        seq: Option<u64>,
     },
     Ice {
-        // ice: RTCIceCandidateInit,
         from: String,
-        to: String,
+        to: Option<String>,
+        candidate: IceCandidate,
+        /// Ask the server to `Ack` or `Nack` this message under the given number.
+        seq: Option<u64>,
+    },
+    /// Like `Offer`, but for a second connection carrying data channels, e.g. chat or file
+    /// transfer, so it can be negotiated without disturbing the media connection.
+    DataOffer {
+        from: String,
+        to: Option<String>,
+        sdp: Value,
+        /// Ask the server to `Ack` or `Nack` this message under the given number.
+        seq: Option<u64>,
+    },
+    DataAnswer {
+        from: String,
+        to: Option<String>,
+        sdp: Value,
+        /// Ask the server to `Ack` or `Nack` this message under the given number.
+        seq: Option<u64>,
+    },
+    DataIce {
+        from: String,
+        to: Option<String>,
+        candidate: IceCandidate,
+        /// Ask the server to `Ack` or `Nack` this message under the given number.
+        seq: Option<u64>,
+    },
+    /// The message the sender numbered `seq` was forwarded.
+    Ack {
+        seq: u64,
+    },
+    /// The message the sender numbered `seq` was not forwarded and may be sent again.
+    Nack {
+        seq: u64,
+        reason: String,
+        code: String,
     },
     Join {
         from: String,
+        /// Room id or room name.
+        room: String,
+        password: Option<String>,
+        /// Join as a spectator, which receives signalling but can't send offers, answers or ICE.
+        #[serde(default)]
+        spectator: bool,
+        /// Auth token, required when the server is started with `--auth-secret`.
+        token: Option<String>,
+    },
+    /// The room hasn't been started yet, or its sharer has yet to admit the viewer. The viewer
+    /// gets `Presence` once it is let in, or `JoinDeclined` if it isn't.
+    JoinPending {
         room: String,
     },
     JoinDeclined {
         to: String,
         reason: String,
+        #[serde(default)]
+        code: String,
+    },
+    /// The server or room is full. Try again after `after_ms` instead of reconnecting straight away.
+    /// It is spread out between clients.
+    Retry {
+        after_ms: u64,
+        reason: String,
+    },
+    Start {
+        /// Lower the server's viewer limit for this room.
+        max_viewers: Option<usize>,
+        /// Require viewers to supply this password to join.
+        password: Option<String>,
+        /// Let viewers join with this name as well as the room id.
+        name: Option<String>,
+        /// Hold joining viewers until a sharer sends `Admit` or `Deny` for them.
+        #[serde(default)]
+        require_admission: bool,
+        /// Auth token, required when the server is started with `--auth-secret`.
+        token: Option<String>,
     },
-    Start {},
     StartResponse {
         room: String,
+        resume_token: String,
+    },
+    /// Reclaim a room after the sharer's connection dropped.
+    Resume {
+        room: String,
+        token: String,
+    },
+    Resumed {
+        room: String,
     },
     Leave {
         from: String,
@@ -47,8 +301,165 @@ pub enum SignallerMessage {
         room: String,
     },
     KeepAlive {},
+    /// Echoed straight back as `AppPong` so clients can measure signalling round trips.
+    AppPing {
+        nonce: String,
+    },
+    AppPong {
+        nonce: String,
+    },
     IceServers {},
     IceServersResponse {
         ice_servers: Vec<IceServer>,
     },
+    Error {
+        message: String,
+        code: String,
+    },
+    WhoAmI {},
+    /// Reply to `WhoAmI`. `role` is "sharer", "viewer", "spectator" or "unregistered".
+    Identity {
+        peer: Option<String>,
+        room: Option<String>,
+        role: String,
+        /// How long this connection has been registered as `peer`.
+        connected_secs: Option<u64>,
+    },
+    /// List the live sessions owned by the holder of resume `token`, or without a token the
+    /// sessions this connection is sharing.
+    MySessions {
+        token: Option<String>,
+    },
+    SessionList {
+        sessions: Vec<SessionInfo>,
+    },
+    /// Receive `AdminStats` every few seconds until disconnecting. `token` must match the server's
+    /// `--admin-token`.
+    AdminSubscribe {
+        token: String,
+    },
+    AdminStats {
+        sessions: usize,
+        peers: usize,
+        /// Messages forwarded per second since the previous `AdminStats`.
+        messages_per_sec: f64,
+    },
+    /// Ask everyone in `room` to reconnect, then close it. Only connections that have sent
+    /// `AdminSubscribe` may send this.
+    DrainRoom {
+        room: String,
+    },
+    /// The room is about to be closed for maintenance. With `reconnect` the client should
+    /// reconnect and start or join again, which may land it on another instance.
+    RoomDraining {
+        reconnect: bool,
+    },
+    ListRooms {},
+    RoomList {
+        rooms: Vec<RoomInfo>,
+    },
+    ViewerCount {
+        count: usize,
+    },
+    /// The server is going away. Reconnect after `reconnect_after_ms`, which is spread out
+    /// between clients.
+    ServerShutdown {
+        #[serde(default)]
+        reconnect_after_ms: u64,
+    },
+    ListViewers {},
+    ViewerList {
+        viewers: Vec<String>,
+        /// Seconds each viewer has been connected.
+        #[serde(default)]
+        connected_secs: BTreeMap<String, u64>,
+    },
+    /// Ask for the counters of the room the sender is sharing. Only sharers may send this.
+    RoomStats {},
+    RoomStatsResponse {
+        viewers: usize,
+        /// Most viewers the room has had at once.
+        peak_viewers: usize,
+        /// Messages forwarded between the room's peers so far.
+        messages_forwarded: u64,
+        uptime_secs: u64,
+    },
+    /// Sent by a sharer to deliver `payload` to every viewer in the room as `BroadcastFrom`.
+    Broadcast {
+        payload: serde_json::Value,
+    },
+    BroadcastFrom {
+        from: String,
+        payload: serde_json::Value,
+    },
+    /// Sent by a sharer whose stream changed so that every viewer renegotiates. Viewers get
+    /// `RenegotiateRequest`.
+    Renegotiate {},
+    /// The sharer is about to send a fresh offer.
+    RenegotiateRequest {},
+    /// Sent by a viewer to ask the sharers for a different simulcast or SVC layer of the stream,
+    /// e.g. "low" on a small screen. `level` must be one of `QUALITY_LEVELS`.
+    QualityRequest {
+        level: String,
+    },
+    /// A viewer's `QualityRequest`, relayed to every sharer of its room.
+    QualityRequestFrom {
+        from: String,
+        level: String,
+    },
+    Kick {
+        target: String,
+    },
+    Kicked {},
+    SessionTimedOut {},
+    /// The room will be closed in `seconds_left` because it reached the maximum session duration.
+    SessionExpiring {
+        seconds_left: u64,
+    },
+    /// The room reached the maximum session duration and was closed.
+    SessionExpired {},
+    PeerJoined {
+        peer: String,
+    },
+    /// Sent to the sharers of a room that requires admission when a viewer asks to join.
+    AdmissionRequest {
+        peer: String,
+    },
+    /// Let a viewer waiting for admission into the room. Any sharer of the room may send this.
+    Admit {
+        target: String,
+    },
+    /// Turn away a viewer waiting for admission, who gets `JoinDeclined`.
+    Deny {
+        target: String,
+    },
+    /// Sent to a viewer when it is admitted, followed by `Presence`.
+    Admitted {},
+    /// Hand the sharer role to a viewer of the room. Only the current sharer may send this.
+    TransferSharer {
+        target: String,
+    },
+    /// Sent to a viewer right after they join, listing everyone in the room including them.
+    Presence {
+        sharer: String,
+        co_sharers: Vec<String>,
+        viewers: Vec<String>,
+        /// Seconds each member has been connected.
+        #[serde(default)]
+        connected_secs: BTreeMap<String, u64>,
+    },
+    /// Let a viewer present alongside the sharer. Any sharer of the room may send this.
+    PromoteToSharer {
+        target: String,
+    },
+    /// Sent to everyone in a room when a viewer becomes a co-sharer.
+    SharerPromoted {
+        peer: String,
+    },
+    /// Sent to everyone in a room when its sharer changes. Only the new sharer gets a token to
+    /// resume the room with.
+    SharerChanged {
+        sharer: String,
+        resume_token: Option<String>,
+    },
 }
@@ -1,45 +1,188 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
 use base64::Engine;
-use failure::{format_err, Error};
-use futures_channel::mpsc::UnboundedSender;
-use log::info;
-use tokio::sync::Mutex;
+use dashmap::mapref::entry::Entry;
+use dashmap::mapref::one::Ref;
+use dashmap::DashMap;
+use rand::{thread_rng, Rng};
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
 use twilio::TwilioAuthentication;
 use warp::ws::Message;
 
+use crate::args::Args;
+use crate::auth;
 use crate::config::Config;
+use crate::connections::ConnectionRegistry;
+use crate::error::{Result, SignallerError};
 use crate::metrics;
-use crate::peer::{Peer, PeerType};
-use crate::session::Session;
-use crate::signaller_message::{IceServer, SignallerMessage};
+use crate::peer::{
+    format_uptime, send_message, Peer, PeerType, Tx, CLOSE_KICKED, CLOSE_TRY_AGAIN_LATER,
+};
+use crate::session::{self, new_resume_token, Session, RESUME_BUFFER_LEN};
+use crate::signaller_message::{
+    Encoding, IceServer, RoomInfo, SessionInfo, SignallerMessage, QUALITY_LEVELS,
+};
 use crate::twilio_helper::get_twilio_ice_servers;
 
-type Result<T> = std::result::Result<T, Error>;
-type Tx = UnboundedSender<Message>;
-
+/// Shared server state. `sessions` and `peers` are locked per entry so unrelated rooms don't
+/// contend. To avoid deadlocks entries are only ever locked in the order `sessions`, `peers`,
+/// `names`, `waiting`, never while holding a later one.
+///
+/// The state a server is created with serves the default namespace and holds one more `State`
+/// per `--namespaces` entry. Rooms and peers of different namespaces never see each other, while
+/// `connections`, the admin subscribers and `password_checks` are shared by all of them.
 pub struct State {
-    pub sessions: HashMap<String, Session>,
-    pub sharer_socket_addr_to_room: HashMap<SocketAddr, String>,
-    pub peers: HashMap<String, Peer>,
+    pub sessions: DashMap<String, Session>,
+    pub peers: DashMap<String, Peer>,
+    /// Room names chosen by sharers, mapped to the room id.
+    pub names: DashMap<String, String>,
+    /// Viewers that asked to join a room before it was started, by the room they asked for.
+    waiting: DashMap<String, Vec<WaitingViewer>>,
+    /// Every open websocket connection, registered as a peer or not.
+    pub connections: Arc<ConnectionRegistry>,
+    /// Connections that subscribed to `AdminStats`.
+    admins: Arc<Mutex<Vec<Tx>>>,
+    /// Permits to hash or verify a room password. argon2 keeps a core busy for a while, so only
+    /// as many run at once as there are cores.
+    password_checks: Arc<Semaphore>,
+    /// The other namespaces by name, only set on the default namespace.
+    namespaces: HashMap<String, StateType>,
     pub twilio_client: Option<twilio::TwilioClient>,
     pub twilio_account_sid: Option<String>,
+    pub ice_servers: Vec<IceServer>,
+    /// How many rooms may be open at once.
+    pub max_rooms: Option<usize>,
+    pub max_viewers: Option<usize>,
+    pub resume_grace: Duration,
+    pub empty_room_ttl: Option<Duration>,
+    /// How long a room may stay open in total.
+    pub max_session_duration: Option<Duration>,
+    /// How long before `max_session_duration` the room's peers are warned.
+    pub session_expiry_warning: Duration,
+    /// How long a connection may stay open without registering as a peer.
+    pub register_timeout: Option<Duration>,
+    /// How long a viewer may wait for a room that hasn't been started yet.
+    pub join_grace: Duration,
+    /// How long clients are asked to wait before reconnecting, before jitter.
+    pub reconnect_delay: Duration,
+    /// Most time added at random to `reconnect_delay`.
+    pub reconnect_jitter: Duration,
+    /// Secret auth tokens on `Start` and `Join` are signed with. Tokens aren't needed without it.
+    pub auth_secret: Option<String>,
+    /// Token `AdminSubscribe` must carry. Nobody may subscribe without it.
+    pub admin_token: Option<String>,
+    /// Message types viewers may send, any if `None`.
+    pub viewer_allowed_messages: Option<HashSet<String>>,
+    /// Lowercased room names nobody may take.
+    pub reserved_rooms: HashSet<String>,
+    /// Lowercased room names that may be taken. Any name may be if empty.
+    pub room_allowlist: HashSet<String>,
+}
+
+pub type StateType = Arc<State>;
+
+/// A `Join` held back until its room is started.
+struct WaitingViewer {
+    id: String,
+    sender: Tx,
+    password: Option<String>,
+    peer_type: PeerType,
+    since: Instant,
 }
 
-pub type StateType = Arc<Mutex<State>>;
+/// Why a session is being torn down.
+#[derive(Clone, Copy, Debug)]
+enum TeardownReason {
+    /// Its last sharer left or was cleaned up after disconnecting.
+    SharerLeft,
+    /// Its sharer disconnected and didn't resume within `resume_grace`.
+    ResumeExpired,
+    /// It went without viewers for longer than `empty_room_ttl`.
+    Empty,
+    /// It reached `max_session_duration`.
+    MaxDuration,
+    /// An admin drained it.
+    Drained,
+}
+
+impl TeardownReason {
+    /// Used in logs and as the `reason` label of `SESSIONS_ENDED`.
+    fn label(self) -> &'static str {
+        match self {
+            TeardownReason::SharerLeft => "sharer_left",
+            TeardownReason::ResumeExpired => "resume_expired",
+            TeardownReason::Empty => "empty",
+            TeardownReason::MaxDuration => "max_duration",
+            TeardownReason::Drained => "drained",
+        }
+    }
+}
+
+/// What became of a viewer's `Join`.
+pub enum JoinStatus {
+    Joined,
+    /// The room doesn't exist yet or requires admission. The viewer is let in once it is started
+    /// or a sharer admits them.
+    Waiting,
+}
 
 impl State {
-    pub fn new(config: &Config) -> StateType {
+    /// The state of the default namespace, along with every namespace in `--namespaces`.
+    pub fn new(config: &Config, args: &Args) -> StateType {
+        let connections = Arc::<ConnectionRegistry>::default();
+        let admins = Arc::<Mutex<Vec<Tx>>>::default();
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        let password_checks = Arc::new(Semaphore::new(cores));
+        let namespaces = args
+            .namespaces
+            .iter()
+            .map(|name| {
+                let state = State::build(
+                    config,
+                    args,
+                    connections.clone(),
+                    admins.clone(),
+                    password_checks.clone(),
+                    HashMap::new(),
+                );
+                (name.clone(), Arc::new(state))
+            })
+            .collect();
+        Arc::new(State::build(
+            config,
+            args,
+            connections,
+            admins,
+            password_checks,
+            namespaces,
+        ))
+    }
+
+    fn build(
+        config: &Config,
+        args: &Args,
+        connections: Arc<ConnectionRegistry>,
+        admins: Arc<Mutex<Vec<Tx>>>,
+        password_checks: Arc<Semaphore>,
+        namespaces: HashMap<String, StateType>,
+    ) -> State {
         let base64_engine = base64::engine::GeneralPurpose::new(
             &base64::alphabet::STANDARD,
             base64::engine::general_purpose::PAD,
         );
-        Arc::new(Mutex::new(State {
+        State {
             sessions: Default::default(),
-            sharer_socket_addr_to_room: Default::default(),
             peers: Default::default(),
+            names: Default::default(),
+            waiting: Default::default(),
+            connections,
+            admins,
+            password_checks,
+            namespaces,
             twilio_client: {
                 if let (Some(account_sid), Some(auth_token)) =
                     (&config.twilio_account_sid, &config.twilio_auth_token)
@@ -56,107 +199,1264 @@ impl State {
                 }
             },
             twilio_account_sid: config.twilio_account_sid.clone(),
-        }))
+            ice_servers: config.ice_servers.clone(),
+            max_rooms: args.max_rooms,
+            max_viewers: args.max_viewers,
+            resume_grace: Duration::from_secs(args.resume_grace),
+            empty_room_ttl: args.empty_room_ttl.map(Duration::from_secs),
+            max_session_duration: args.max_session_duration.map(Duration::from_secs),
+            session_expiry_warning: Duration::from_secs(args.session_expiry_warning),
+            register_timeout: args.register_timeout.map(Duration::from_secs),
+            join_grace: Duration::from_secs(args.join_grace),
+            reconnect_delay: Duration::from_millis(args.reconnect_delay_ms),
+            reconnect_jitter: Duration::from_millis(args.reconnect_jitter_ms),
+            auth_secret: args.auth_secret.clone(),
+            admin_token: args.admin_token.clone(),
+            viewer_allowed_messages: match args.viewer_allowed_messages.as_slice() {
+                [] => None,
+                types => Some(types.iter().cloned().collect()),
+            },
+            reserved_rooms: args
+                .reserved_rooms
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+            room_allowlist: args
+                .room_allowlist
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+        }
     }
 
-    pub fn add_sharer(&mut self, room: String, sender: Tx, socket_addr: SocketAddr) -> Result<()> {
-        if self.sessions.contains_key(&room) {
-            return Err(format_err!("room already exists"));
+    /// The state of namespace `name`, if it is one of `--namespaces`.
+    pub fn namespace(&self, name: &str) -> Option<&StateType> {
+        self.namespaces.get(name)
+    }
+
+    /// This namespace followed by the others. Anything server-wide has to look at all of them.
+    pub fn with_namespaces(&self) -> impl Iterator<Item = &State> {
+        std::iter::once(self).chain(self.namespaces.values().map(|state| state.as_ref()))
+    }
+
+    /// Register a sharer and open their room. Returns the token the sharer can resume it with.
+    pub fn add_sharer(
+        &self,
+        room: String,
+        sender: Tx,
+        max_viewers: Option<usize>,
+        password_hash: Option<String>,
+        name: Option<String>,
+        require_admission: bool,
+    ) -> Result<String> {
+        // Counting locks every shard, so it has to happen before any entry is held. Rooms started
+        // at the same moment can overshoot the limit by a few.
+        if self.max_rooms.is_some_and(|max| self.sessions.len() >= max) {
+            return Err(SignallerError::ServerAtCapacity);
         }
-        self.sessions
-            .insert(room.clone(), Session::new(room.clone(), socket_addr));
-        self.sharer_socket_addr_to_room
-            .insert(socket_addr, room.clone());
-        metrics::NUM_ONGOING_SESSIONS.inc();
-        self.peers.insert(
-            room.clone(),
-            Peer {
-                room,
-                sender,
-                peer_type: PeerType::Sharer {},
+        let Entry::Vacant(session_entry) = self.sessions.entry(room.clone()) else {
+            return Err(SignallerError::RoomExists);
+        };
+        let Entry::Vacant(peer_entry) = self.peers.entry(room.clone()) else {
+            return Err(SignallerError::PeerExists);
+        };
+        if name
+            .as_deref()
+            .is_some_and(|name| !self.room_name_allowed(name))
+        {
+            return Err(SignallerError::RoomForbidden);
+        }
+        let name_entry = match &name {
+            Some(name) => match self.names.entry(name.clone()) {
+                Entry::Vacant(entry) => Some(entry),
+                Entry::Occupied(_) => return Err(SignallerError::NameTaken),
             },
+            None => None,
+        };
+        // A room may only tighten the server-wide limit, never lift it.
+        let max_viewers = match (max_viewers, self.max_viewers) {
+            (Some(room_max), Some(server_max)) => Some(room_max.min(server_max)),
+            (room_max, server_max) => room_max.or(server_max),
+        };
+        let session = Session::new(
+            room.clone(),
+            max_viewers,
+            password_hash,
+            name.clone(),
+            require_admission,
         );
-        Ok(())
+        let resume_token = session.resume_token.clone();
+        if let Some(entry) = name_entry {
+            entry.insert(room.clone());
+        }
+        peer_entry.insert(Peer {
+            room: room.clone(),
+            sender,
+            peer_type: PeerType::Sharer {},
+            connected_at: Instant::now(),
+        });
+        session_entry.insert(session);
+        metrics::NUM_ONGOING_SESSIONS.inc();
+        Ok(resume_token)
     }
 
-    pub fn add_viewer(&mut self, id: String, room: String, sender: Tx) -> Result<()> {
-        if !self.sessions.contains_key(&room) {
-            return Err(format_err!("room does not exist"));
+    /// Let in the viewers that asked for `room`, by id or by name, before it was started. Called
+    /// once the sharer has its `StartResponse`, so it knows its room before anyone joins it.
+    pub async fn admit_early_viewers(&self, room: &str) {
+        let name = self
+            .sessions
+            .get(room)
+            .and_then(|session| session.name.clone());
+        for key in [Some(room), name.as_deref()].into_iter().flatten() {
+            self.admit_waiting(room, key).await;
         }
-        self.sessions
-            .get_mut(&room)
-            .unwrap()
-            .viewers
-            .insert(id.clone());
-        self.peers.insert(
-            id,
-            Peer {
-                room,
+    }
+
+    /// Add a viewer to a room. `peer_type` is `Viewer` or, for viewers that may only listen,
+    /// `Spectator`.
+    pub async fn add_viewer(
+        &self,
+        id: String,
+        room: String,
+        sender: Tx,
+        password: Option<&str>,
+        peer_type: PeerType,
+    ) -> Result<JoinStatus> {
+        let room = self.resolve_room(room);
+        if !self.join_grace.is_zero() && !self.sessions.contains_key(&room) {
+            return self
+                .wait_for_room(id, room, sender, password, peer_type)
+                .await;
+        }
+        let password_hash = self.verify_password(&room, password).await?;
+        self.join_room(id, room, sender, password_hash.as_deref(), peer_type)
+    }
+
+    /// Check `password` against the password of `room`, if it has one. Argon2 is deliberately
+    /// slow, so this runs on the blocking pool without any lock held. Returns the hash it matched,
+    /// which `join_room` checks is still the room's.
+    /// Hash the password of a room being started, off the async workers.
+    pub async fn hash_password(&self, password: String) -> Result<String> {
+        // The semaphore is never closed.
+        let _permit = self.password_checks.acquire().await;
+        let hash = tokio::task::spawn_blocking(move || session::hash_password(&password))
+            .await
+            .expect("hashing a password doesn't panic")?;
+        Ok(hash)
+    }
+
+    async fn verify_password(&self, room: &str, password: Option<&str>) -> Result<Option<String>> {
+        let Some(hash) = self
+            .sessions
+            .get(room)
+            .and_then(|session| session.password_hash.clone())
+        else {
+            return Ok(None);
+        };
+        let Some(password) = password.map(str::to_string) else {
+            return Err(SignallerError::InvalidPassword);
+        };
+        // The semaphore is never closed.
+        let _permit = self.password_checks.acquire().await;
+        let verified = tokio::task::spawn_blocking({
+            let hash = hash.clone();
+            move || session::verify_password(&hash, &password)
+        })
+        .await
+        .unwrap_or(false);
+        if !verified {
+            return Err(SignallerError::InvalidPassword);
+        }
+        Ok(Some(hash))
+    }
+
+    /// Put a viewer into a room whose password, if any, `verify_password` matched against
+    /// `password_hash`.
+    fn join_room(
+        &self,
+        id: String,
+        room: String,
+        sender: Tx,
+        password_hash: Option<&str>,
+        peer_type: PeerType,
+    ) -> Result<JoinStatus> {
+        let sharers = {
+            let mut session = self
+                .sessions
+                .get_mut(&room)
+                .ok_or(SignallerError::RoomNotFound)?;
+            if session.closing {
+                return Err(SignallerError::SessionClosing);
+            }
+            // A sharer can't also be a viewer, teardown would treat them as both.
+            if session.sharers().any(|sharer| *sharer == id) {
+                return Err(SignallerError::AlreadySharer);
+            }
+            let Entry::Vacant(peer_entry) = self.peers.entry(id.clone()) else {
+                return Err(SignallerError::PeerExists);
+            };
+            if session
+                .max_viewers
+                .is_some_and(|max| session.viewers.len() >= max)
+            {
+                return Err(SignallerError::RoomFull);
+            }
+            // The room may have been closed and started again with another password meanwhile.
+            if session.password_hash.as_deref() != password_hash {
+                return Err(SignallerError::InvalidPassword);
+            }
+            if session.require_admission {
+                session.pending.insert(id.clone());
+                peer_entry.insert(Peer {
+                    room: room.clone(),
+                    sender,
+                    peer_type,
+                    connected_at: Instant::now(),
+                });
+                let sharers: Vec<String> = session.sharers().cloned().collect();
+                drop(session);
+                info!("{} is waiting to be admitted to room {}", id, room);
+                for sharer in &sharers {
+                    self.notify(
+                        sharer,
+                        &SignallerMessage::AdmissionRequest { peer: id.clone() },
+                    );
+                }
+                return Ok(JoinStatus::Waiting);
+            }
+            session.viewers.insert(id.clone());
+            session.peak_viewers = session.peak_viewers.max(session.viewers.len());
+            session.empty_since = None;
+            peer_entry.insert(Peer {
+                room: room.clone(),
+                sender: sender.clone(),
+                peer_type,
+                connected_at: Instant::now(),
+            });
+            // Taken after the join so the new viewer sees themselves.
+            send_message(&sender, &self.presence(&session));
+            session.sharers().cloned().collect::<Vec<_>>()
+        };
+        for sharer in &sharers {
+            self.notify(sharer, &SignallerMessage::PeerJoined { peer: id.clone() });
+        }
+        self.notify_viewer_count(&room);
+        Ok(JoinStatus::Joined)
+    }
+
+    /// Hold on to a viewer whose room hasn't been started yet, for up to `join_grace`.
+    async fn wait_for_room(
+        &self,
+        id: String,
+        room: String,
+        sender: Tx,
+        password: Option<&str>,
+        peer_type: PeerType,
+    ) -> Result<JoinStatus> {
+        if self.peers.contains_key(&id) {
+            return Err(SignallerError::PeerExists);
+        }
+        info!("{} is waiting for room {}", id, room);
+        self.waiting
+            .entry(room.clone())
+            .or_default()
+            .push(WaitingViewer {
+                id,
                 sender,
-                peer_type: PeerType::Viewer {},
-            },
-        );
-        Ok(())
+                password: password.map(str::to_string),
+                peer_type,
+                since: Instant::now(),
+            });
+        // The room may have been started while the viewer was being queued.
+        let started = self.resolve_room(room.clone());
+        if self.sessions.contains_key(&started) {
+            self.admit_waiting(&started, &room).await;
+        }
+        Ok(JoinStatus::Waiting)
     }
 
-    fn remove_session(&mut self, room: &String) {
-        info!("Removing session {}", room);
-        let session = self.sessions.remove(room).unwrap();
-        self.sharer_socket_addr_to_room
-            .remove(&session.sharer_socket_addr);
-        let duration_sec = session.start_time.elapsed().unwrap().as_secs_f64();
-        info!("Ended session with duration: {}s", duration_sec);
+    /// Let the viewers waiting under `key` into the newly started `room`.
+    async fn admit_waiting(&self, room: &str, key: &str) {
+        let Some((_, waiting)) = self.waiting.remove(key) else {
+            return;
+        };
+        for viewer in waiting {
+            // Viewers that gave up or disconnected meanwhile are simply forgotten.
+            if viewer.since.elapsed() > self.join_grace || viewer.sender.is_closed() {
+                continue;
+            }
+            let joined = self
+                .verify_password(room, viewer.password.as_deref())
+                .await
+                .and_then(|password_hash| {
+                    self.join_room(
+                        viewer.id.clone(),
+                        room.to_string(),
+                        viewer.sender.clone(),
+                        password_hash.as_deref(),
+                        viewer.peer_type,
+                    )
+                });
+            if let Err(e) = joined {
+                decline_join(&viewer, &e);
+            }
+        }
+    }
+
+    /// Turn away viewers that waited longer than `join_grace` for their room. Called periodically.
+    pub fn reap_waiting(&self) {
+        self.waiting.retain(|_, waiting| {
+            waiting.retain(|viewer| {
+                if viewer.since.elapsed() <= self.join_grace {
+                    return true;
+                }
+                decline_join(viewer, &SignallerError::RoomNotFound);
+                false
+            });
+            !waiting.is_empty()
+        });
+    }
+
+    /// Close `room` if `only_if` holds for it, telling its peers why and dropping them. Every way
+    /// a session can end goes through here. Returns whether the room was closed.
+    fn destroy_session(
+        &self,
+        room: &str,
+        reason: TeardownReason,
+        only_if: impl FnOnce(&Session) -> bool,
+    ) -> bool {
+        let Some((_, session)) = self.sessions.remove_if(room, |_, session| only_if(session))
+        else {
+            return false;
+        };
+        info!("Removing session {} ({})", room, reason.label());
+        match reason {
+            TeardownReason::Empty => {
+                for sharer in session.sharers() {
+                    self.notify(sharer, &SignallerMessage::SessionTimedOut {});
+                }
+            }
+            TeardownReason::MaxDuration => {
+                for member in session.members() {
+                    self.notify(&member, &SignallerMessage::SessionExpired {});
+                }
+            }
+            TeardownReason::SharerLeft
+            | TeardownReason::ResumeExpired
+            | TeardownReason::Drained => {}
+        }
+        if let Some(name) = &session.name {
+            self.names.remove_if(name, |_, named| named == room);
+        }
+        let duration_sec = session.started_at.elapsed().as_secs_f64();
+        info!(
+            "Ended session {} with duration: {}s, peak viewers: {}, messages forwarded: {}",
+            room,
+            duration_sec,
+            session.peak_viewers,
+            session.messages_forwarded.load(Ordering::Relaxed)
+        );
         metrics::NUM_ONGOING_SESSIONS.dec();
+        metrics::SESSIONS_ENDED
+            .with_label_values(&[reason.label()])
+            .inc();
         metrics::SESSION_DURATION_SEC.observe(duration_sec);
-        for viewer in session.viewers {
-            let _ = self.peers[&viewer].sender.unbounded_send(Message::text(
-                serde_json::to_string(&SignallerMessage::RoomClosed {
-                    to: viewer.clone(),
-                    room: room.clone(),
-                })
-                .unwrap(),
-            ));
-            self.peers.remove(&viewer);
+        metrics::SESSION_PEAK_VIEWERS.observe(session.peak_viewers as f64);
+        // The id may already belong to a peer in another room if it left and rejoined meanwhile.
+        let in_room = |_: &String, peer: &Peer| peer.room == room;
+        for id in session
+            .viewers
+            .iter()
+            .chain(&session.co_sharers)
+            .chain(&session.pending)
+        {
+            // A peer that can't be reached must not stop the others from being notified.
+            let Some((_, peer)) = self.peers.remove_if(id, in_room) else {
+                continue;
+            };
+            info!(
+                "{} connected for {}",
+                id,
+                format_uptime(peer.connected_secs())
+            );
+            send_message(
+                &peer.sender,
+                &SignallerMessage::RoomClosed {
+                    to: id.clone(),
+                    room: room.to_string(),
+                },
+            );
+        }
+        if let Some((id, peer)) = self.peers.remove_if(&session.sharer, in_room) {
+            info!(
+                "{} connected for {}",
+                id,
+                format_uptime(peer.connected_secs())
+            );
+        }
+        true
+    }
+
+    /// Check the auth token sent with a `Start` or `Join` for `room`, if tokens are required.
+    pub fn authorize(&self, token: Option<&str>, room: Option<&str>) -> Result<()> {
+        match &self.auth_secret {
+            Some(secret) => auth::verify_token(secret, token, room),
+            None => Ok(()),
+        }
+    }
+
+    /// Start sending `AdminStats` to `sender` if `token` is the server's admin token.
+    pub fn subscribe_admin(&self, token: &str, sender: Tx) -> Result<()> {
+        match &self.admin_token {
+            Some(expected) if auth::admin_token_matches(expected, token) => {
+                self.lock_admins().push(sender);
+                Ok(())
+            }
+            _ => Err(SignallerError::Unauthorized),
+        }
+    }
+
+    /// Send the current stats to every admin subscriber, forgetting those that disconnected.
+    /// Called periodically.
+    pub fn push_admin_stats(&self, messages_per_sec: f64) {
+        let msg = SignallerMessage::AdminStats {
+            sessions: self
+                .with_namespaces()
+                .map(|state| state.sessions.len())
+                .sum(),
+            peers: self.with_namespaces().map(|state| state.peers.len()).sum(),
+            messages_per_sec,
+        };
+        let mut admins = self.lock_admins();
+        admins.retain(|admin| !admin.is_closed());
+        for admin in admins.iter() {
+            send_message(admin, &msg);
+        }
+    }
+
+    fn lock_admins(&self) -> MutexGuard<'_, Vec<Tx>> {
+        self.admins.lock().unwrap_or_else(|e| {
+            warn!("Admin subscriber lock was poisoned, recovering it");
+            e.into_inner()
+        })
+    }
+
+    fn room_name_allowed(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        !self.reserved_rooms.contains(&name)
+            && (self.room_allowlist.is_empty() || self.room_allowlist.contains(&name))
+    }
+
+    /// The room id `room` refers to, looking it up as a room name if it isn't an id itself.
+    fn resolve_room(&self, room: String) -> String {
+        if self.sessions.contains_key(&room) {
+            return room;
+        }
+        match self.names.get(&room) {
+            Some(named) => named.clone(),
+            None => room,
+        }
+    }
+
+    /// Whether `id` is still the peer registered by the connection `sender` writes to. The server
+    /// drops peers on its own, e.g. when their room closes, after which someone else may take
+    /// the id.
+    pub fn registered_as(&self, id: &str, sender: &Tx) -> bool {
+        if let Some(peer) = self.peers.get(id) {
+            return peer.sender.same_connection(sender);
         }
-        self.peers.remove(&session.sharer);
+        self.waiting.iter().any(|waiting| {
+            waiting
+                .iter()
+                .any(|viewer| viewer.id == id && viewer.sender.same_connection(sender))
+        })
     }
 
     /// Leave a session. id is the id of the viewer or the sharer.
-    pub fn leave_session(&mut self, id: String) -> Result<()> {
-        if self.sessions.contains_key(&id) {
-            // id is host. remove session
-            self.remove_session(&id);
-        } else {
-            let peer = self
-                .peers
-                .get(&id)
-                .ok_or_else(|| format_err!("Peer does not exist"))?;
-            let session = self.sessions.get_mut(&peer.room).unwrap();
-            session.viewers.remove(&id);
-            self.peers.remove(&id);
+    pub fn leave_session(&self, id: String) -> Result<()> {
+        if !self.peers.contains_key(&id) {
+            return Err(SignallerError::PeerNotFound);
+        }
+        self.cleanup_peer(&id)
+    }
+
+    /// Remove a peer whose connection has gone away. If the peer was the room's last sharer, the
+    /// whole session is torn down, otherwise only the peer is removed and the room is told.
+    /// Peers that already left are ignored.
+    pub fn cleanup_peer(&self, id: &str) -> Result<()> {
+        let Some((room, peer_type)) = self
+            .peers
+            .get(id)
+            .map(|peer| (peer.room.clone(), peer.peer_type))
+        else {
+            return Ok(());
+        };
+        match peer_type {
+            PeerType::Sharer {} => self.remove_sharer(&room, id)?,
+            PeerType::Viewer {} | PeerType::Spectator {} => {
+                let Some((_, peer)) = self.peers.remove_if(id, |_, peer| peer.room == room) else {
+                    return Ok(());
+                };
+                info!(
+                    "{} connected for {}",
+                    id,
+                    format_uptime(peer.connected_secs())
+                );
+                let sharers: Vec<String> = match self.sessions.get_mut(&room) {
+                    Some(mut session) => {
+                        session.viewers.remove(id);
+                        session.pending.remove(id);
+                        if session.viewers.is_empty() {
+                            session.empty_since = Some(Instant::now());
+                        }
+                        session.sharers().cloned().collect()
+                    }
+                    None => return Ok(()),
+                };
+                for sharer in &sharers {
+                    self.notify(
+                        sharer,
+                        &SignallerMessage::Leave {
+                            from: id.to_string(),
+                        },
+                    );
+                }
+                self.notify_viewer_count(&room);
+            }
+        }
+        Ok(())
+    }
+
+    /// Take a sharer out of their room. The room only closes once its last sharer is gone, until
+    /// then the owner's place and resume token pass on to a co-sharer.
+    fn remove_sharer(&self, room: &str, id: &str) -> Result<()> {
+        let promoted = {
+            let mut session = self
+                .sessions
+                .get_mut(room)
+                .ok_or(SignallerError::RoomNotFound)?;
+            if session.co_sharers.remove(id) {
+                None
+            } else {
+                let next = session.co_sharers.iter().min().cloned();
+                let Some(next) = next else {
+                    // Joins that get in before the room is removed must not land in it.
+                    session.closing = true;
+                    drop(session);
+                    if !self.destroy_session(room, TeardownReason::SharerLeft, |_| true) {
+                        return Err(SignallerError::RoomNotFound);
+                    }
+                    return Ok(());
+                };
+                session.co_sharers.remove(&next);
+                session.sharer = next.clone();
+                session.sharer_disconnected_at = None;
+                // Whatever was held for the old owner is of no use to the new one.
+                session.resume_buffer.clear();
+                session.resume_token = new_resume_token();
+                Some((next, session.resume_token.clone()))
+            }
+        };
+        if let Some((_, peer)) = self.peers.remove_if(id, |_, peer| peer.room == room) {
+            info!(
+                "{} connected for {}",
+                id,
+                format_uptime(peer.connected_secs())
+            );
+        }
+        let Some(members) = self.sessions.get(room).map(|session| session.members()) else {
+            return Ok(());
+        };
+        for member in &members {
+            self.notify(
+                member,
+                &SignallerMessage::Leave {
+                    from: id.to_string(),
+                },
+            );
+        }
+        if let Some((sharer, resume_token)) = promoted {
+            info!("{} took over room {} from {}", sharer, room, id);
+            self.announce_sharer(&members, &sharer, resume_token);
         }
         Ok(())
     }
 
-    pub fn on_disconnect(&mut self, socket_addr: &SocketAddr) {
-        if let Some(room) = self.sharer_socket_addr_to_room.get(socket_addr) {
-            self.remove_session(&room.clone());
+    /// Tell the sharers how many viewers are currently in their room.
+    fn notify_viewer_count(&self, room: &str) {
+        if let Some(session) = self.sessions.get(room) {
+            for sharer in session.sharers() {
+                self.notify(
+                    sharer,
+                    &SignallerMessage::ViewerCount {
+                        count: session.viewers.len(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Handle a peer's connection going away. A sharer's room is kept for `resume_grace` so they
+    /// can `Resume` it; in that case the room is returned and the caller must call
+    /// `expire_disconnected_sharer` once the grace period is over.
+    pub fn disconnect_peer(&self, id: &str) -> Result<Option<String>> {
+        // Only the sharer that owns the room can resume it, co-sharers just leave.
+        let owned_room = self
+            .peers
+            .get(id)
+            .map(|peer| peer.room.clone())
+            .filter(|room| {
+                self.sessions
+                    .get(room)
+                    .is_some_and(|session| session.sharer == id)
+            });
+        let Some(room) = owned_room.filter(|_| !self.resume_grace.is_zero()) else {
+            return self.cleanup_peer(id).map(|_| None);
+        };
+        let mut session = self
+            .sessions
+            .get_mut(&room)
+            .ok_or(SignallerError::RoomNotFound)?;
+        info!("Sharer of {} disconnected, keeping room for resume", room);
+        session.sharer_disconnected_at = Some(Instant::now());
+        Ok(Some(room))
+    }
+
+    /// Hold on to a message for `id` whose connection is gone, if it owns `room` and may still
+    /// resume it. Returns false if the message can't be held and is lost.
+    pub fn buffer_for_resume(
+        &self,
+        room: &str,
+        id: &str,
+        encoding: Encoding,
+        frame: Message,
+    ) -> bool {
+        if self.resume_grace.is_zero() {
+            return false;
+        }
+        let Some(mut session) = self.sessions.get_mut(room) else {
+            return false;
+        };
+        if session.sharer != id || session.resume_buffer.len() >= RESUME_BUFFER_LEN {
+            return false;
+        }
+        session.resume_buffer.push_back((encoding, frame));
+        true
+    }
+
+    /// Tear down a room whose sharer disconnected and did not resume within the grace period.
+    pub fn expire_disconnected_sharer(&self, room: &str) -> Result<()> {
+        let expired = |session: &Session| {
+            session
+                .sharer_disconnected_at
+                .is_some_and(|at| at.elapsed() >= self.resume_grace)
+        };
+        let removed = self.destroy_session(room, TeardownReason::ResumeExpired, |session| {
+            expired(session) && session.co_sharers.is_empty()
+        });
+        if removed {
+            return Ok(());
+        }
+        // Co-sharers keep the room going without its owner.
+        let sharer = self
+            .sessions
+            .get(room)
+            .filter(|session| expired(session.value()))
+            .map(|session| session.sharer.clone());
+        match sharer {
+            Some(sharer) => self.remove_sharer(room, &sharer),
+            None => Ok(()),
+        }
+    }
+
+    /// Re-attach a sharer that lost their connection to their room. Returns the sharer's peer id,
+    /// which is no longer the room id once the room has changed hands.
+    pub fn resume_sharer(&self, room: &str, token: &str, sender: Tx) -> Result<String> {
+        let mut session = self
+            .sessions
+            .get_mut(room)
+            .ok_or(SignallerError::RoomNotFound)?;
+        if session.resume_token != token {
+            return Err(SignallerError::InvalidResumeToken);
+        }
+        if session.sharer_disconnected_at.is_none() {
+            return Err(SignallerError::SharerConnected);
+        }
+        session.sharer_disconnected_at = None;
+        let mut peer = self
+            .peers
+            .get_mut(&session.sharer)
+            .ok_or(SignallerError::PeerNotFound)?;
+        peer.sender = sender;
+        peer.connected_at = Instant::now();
+        info!("Sharer resumed room {}", room);
+        for (encoding, frame) in session.resume_buffer.drain(..) {
+            // Held frames are in the old connection's encoding, which the new one may not speak.
+            let frame = if encoding == peer.sender.encoding {
+                frame
+            } else {
+                match encoding.decode(&frame) {
+                    Ok(msg) => peer.sender.encoding.encode(&msg),
+                    Err(_) => continue,
+                }
+            };
+            if let Err(e) = peer.sender.send(frame) {
+                info!("Error delivering held message: {}", e);
+            }
+        }
+        Ok(session.sharer.clone())
+    }
+
+    /// Tear down rooms that have outlived their limits. Called periodically.
+    pub fn reap_sessions(&self) {
+        self.close_empty_rooms();
+        self.close_expired_rooms();
+    }
+
+    /// Close rooms that have gone without viewers for longer than `empty_room_ttl`.
+    fn close_empty_rooms(&self) {
+        let Some(ttl) = self.empty_room_ttl else {
+            return;
+        };
+        let is_expired =
+            |session: &Session| session.empty_since.is_some_and(|at| at.elapsed() > ttl);
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|entry| is_expired(entry.value()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        for room in expired {
+            // A viewer may have joined since the scan.
+            self.destroy_session(&room, TeardownReason::Empty, is_expired);
         }
     }
 
-    pub fn get_room_id_from_peer_uuid(&self, viewer_uuid: &String) -> Result<String> {
-        let peer = self
+    /// Warn rooms nearing `max_session_duration`, once each, and close the ones that reached it.
+    fn close_expired_rooms(&self) {
+        let Some(max) = self.max_session_duration else {
+            return;
+        };
+        let warn_after = max.saturating_sub(self.session_expiry_warning);
+        let mut expired = vec![];
+        let mut warnings = vec![];
+        for mut entry in self.sessions.iter_mut() {
+            let age = entry.started_at.elapsed();
+            if age >= max {
+                expired.push(entry.key().clone());
+            } else if age >= warn_after && !entry.expiry_warned {
+                entry.expiry_warned = true;
+                warnings.push((entry.members(), (max - age).as_secs()));
+            }
+        }
+        for (members, seconds_left) in warnings {
+            for member in &members {
+                self.notify(member, &SignallerMessage::SessionExpiring { seconds_left });
+            }
+        }
+        for room in expired {
+            self.destroy_session(&room, TeardownReason::MaxDuration, |_| true);
+        }
+    }
+
+    /// Tell everyone in `room`, which may also be a room name, that it is about to close and stop
+    /// letting viewers in. Returns the room id to pass to `finish_drain` after a grace period.
+    pub fn drain_room(&self, room: String) -> Result<String> {
+        let room = self.resolve_room(room);
+        let members = {
+            let mut session = self
+                .sessions
+                .get_mut(&room)
+                .ok_or(SignallerError::RoomNotFound)?;
+            session.closing = true;
+            session.members()
+        };
+        info!("Draining room {}", room);
+        for member in &members {
+            self.notify(member, &SignallerMessage::RoomDraining { reconnect: true });
+        }
+        Ok(room)
+    }
+
+    /// Close a room `drain_room` was called for, unless it has been closed meanwhile.
+    pub fn finish_drain(&self, room: &str) {
+        self.destroy_session(room, TeardownReason::Drained, |session| session.closing);
+    }
+
+    /// Count a message forwarded within `room` towards its session summary.
+    pub fn count_forward(&self, room: &str) {
+        if let Some(session) = self.sessions.get(room) {
+            session.messages_forwarded.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Send a message to a peer, logging instead of failing if it can't be delivered.
+    fn notify(&self, id: &str, msg: &SignallerMessage) {
+        if let Some(peer) = self.peers.get(id) {
+            send_message(&peer.sender, msg);
+        }
+    }
+
+    /// Check that `id` may send a message of type `kind`, if it is a viewer and viewers are
+    /// restricted by `--viewer-allowed-messages`.
+    pub fn check_viewer_message(&self, id: &str, kind: &str) -> Result<()> {
+        let Some(allowed) = &self.viewer_allowed_messages else {
+            return Ok(());
+        };
+        // Viewers must always be able to keep their connection alive and leave.
+        if matches!(kind, "leave" | "keep_alive") || allowed.contains(kind) {
+            return Ok(());
+        }
+        let is_viewer = self
             .peers
-            .get(viewer_uuid)
-            .ok_or_else(|| format_err!("Peer does not exist"))?;
+            .get(id)
+            .is_some_and(|peer| matches!(peer.peer_type, PeerType::Viewer {}));
+        if is_viewer {
+            return Err(SignallerError::ViewerForbidden(kind.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Check that `from` may send signalling messages to `to`, i.e. both are different peers in
+    /// the same room and `from` isn't a spectator.
+    pub fn check_route(&self, from: &str, to: &str) -> Result<()> {
+        if from == to {
+            return Err(SignallerError::SelfAddressed);
+        }
+        let sender_room = {
+            let sender = self.peers.get(from).ok_or(SignallerError::PeerNotFound)?;
+            if matches!(sender.peer_type, PeerType::Spectator {}) {
+                return Err(SignallerError::Forbidden);
+            }
+            sender.room.clone()
+        };
+        let recipient_room = self
+            .peers
+            .get(to)
+            .ok_or(SignallerError::PeerNotFound)?
+            .room
+            .clone();
+        if sender_room != recipient_room {
+            return Err(SignallerError::NotInRoom);
+        }
+        // Viewers waiting to be admitted are kept out of the room's signalling both ways.
+        if self
+            .sessions
+            .get(&sender_room)
+            .is_some_and(|session| session.pending.contains(from) || session.pending.contains(to))
+        {
+            return Err(SignallerError::AwaitingAdmission);
+        }
+        Ok(())
+    }
+
+    /// Like `check_route`, but for an `Answer`, which only a viewer may send and only to a sharer
+    /// of its room. Anything else could be a spoofed answer to someone else's offer.
+    pub fn check_answer_route(&self, from: &str, to: &str) -> Result<()> {
+        self.check_route(from, to)?;
+        let sender = self.peers.get(from).ok_or(SignallerError::PeerNotFound)?;
+        if !matches!(sender.peer_type, PeerType::Viewer {}) {
+            return Err(SignallerError::BadRoute);
+        }
+        drop(sender);
+        let recipient = self.peers.get(to).ok_or(SignallerError::PeerNotFound)?;
+        if !matches!(recipient.peer_type, PeerType::Sharer {}) {
+            return Err(SignallerError::BadRoute);
+        }
+        Ok(())
+    }
+
+    /// The session `id` is the sharer of, or an error if they aren't sharing.
+    fn sharer_session(&self, id: &str) -> Result<Ref<'_, String, Session>> {
+        let room = self.sharer_room(id)?;
+        self.sessions.get(&room).ok_or(SignallerError::RoomNotFound)
+    }
+
+    /// The room `id` is the sharer of, or an error if they aren't sharing.
+    fn sharer_room(&self, id: &str) -> Result<String> {
+        let peer = self.peers.get(id).ok_or(SignallerError::PeerNotFound)?;
+        if !matches!(peer.peer_type, PeerType::Sharer {}) {
+            return Err(SignallerError::NotSharer);
+        }
         Ok(peer.room.clone())
     }
 
+    /// The viewers in the room `sharer` is sharing, sorted.
+    pub fn room_viewers(&self, sharer: &str) -> Result<Vec<String>> {
+        let mut viewers: Vec<String> = self
+            .sharer_session(sharer)?
+            .viewers
+            .iter()
+            .cloned()
+            .collect();
+        viewers.sort();
+        Ok(viewers)
+    }
+
+    pub fn list_viewers(&self, sharer: &str) -> Result<SignallerMessage> {
+        let viewers = self.room_viewers(sharer)?;
+        Ok(SignallerMessage::ViewerList {
+            connected_secs: self.connected_secs(&viewers),
+            viewers,
+        })
+    }
+
+    /// Counters for the room `sharer` is sharing.
+    pub fn room_stats(&self, sharer: &str) -> Result<SignallerMessage> {
+        let session = self.sharer_session(sharer)?;
+        Ok(SignallerMessage::RoomStatsResponse {
+            viewers: session.viewers.len(),
+            peak_viewers: session.peak_viewers,
+            messages_forwarded: session.messages_forwarded.load(Ordering::Relaxed),
+            uptime_secs: session.started_at.elapsed().as_secs(),
+        })
+    }
+
+    /// A room's `Presence`. Looks every member up in `peers`, so no `peers` guard may be held.
+    fn presence(&self, session: &Session) -> SignallerMessage {
+        session.presence(self.connected_secs(&session.members()))
+    }
+
+    /// How long each of `ids` has been connected, leaving out any that have gone away.
+    fn connected_secs(&self, ids: &[String]) -> BTreeMap<String, u64> {
+        ids.iter()
+            .filter_map(|id| Some((id.clone(), self.peers.get(id)?.connected_secs())))
+            .collect()
+    }
+
+    /// Send `payload` from a sharer to every viewer in their room. Viewers that have gone away are
+    /// skipped rather than failing the whole broadcast.
+    pub fn broadcast(&self, sharer: &str, payload: serde_json::Value) -> Result<()> {
+        let session = self.sharer_session(sharer)?;
+        let msg = SignallerMessage::BroadcastFrom {
+            from: sharer.to_string(),
+            payload,
+        };
+        for viewer in &session.viewers {
+            self.notify(viewer, &msg);
+        }
+        session.messages_forwarded.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Ask every viewer in the sharer's room to expect a fresh offer, e.g. after the sharer
+    /// switched screens. Viewers may not send this.
+    pub fn request_renegotiation(&self, sharer: &str) -> Result<()> {
+        let session = self.sharer_session(sharer).map_err(|e| match e {
+            SignallerError::NotSharer => SignallerError::RenegotiateForbidden,
+            e => e,
+        })?;
+        for viewer in &session.viewers {
+            self.notify(viewer, &SignallerMessage::RenegotiateRequest {});
+        }
+        session.messages_forwarded.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Pass a viewer's preferred stream quality on to the sharers of its room.
+    pub fn request_quality(&self, viewer: &str, level: String) -> Result<()> {
+        if !QUALITY_LEVELS.contains(&level.as_str()) {
+            return Err(SignallerError::UnknownQualityLevel(level));
+        }
+        let room = {
+            let peer = self.peers.get(viewer).ok_or(SignallerError::PeerNotFound)?;
+            if matches!(peer.peer_type, PeerType::Sharer {}) {
+                return Err(SignallerError::NotViewer);
+            }
+            peer.room.clone()
+        };
+        let session = self
+            .sessions
+            .get(&room)
+            .ok_or(SignallerError::RoomNotFound)?;
+        if session.pending.contains(viewer) {
+            return Err(SignallerError::AwaitingAdmission);
+        }
+        let msg = SignallerMessage::QualityRequestFrom {
+            from: viewer.to_string(),
+            level,
+        };
+        for sharer in session.sharers() {
+            self.notify(sharer, &msg);
+        }
+        session.messages_forwarded.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Remove a viewer from the sharer's room and close their connection.
+    pub fn kick(&self, sharer: &str, target: &str) -> Result<()> {
+        let in_room = self.sharer_session(sharer)?.viewers.contains(target);
+        if !in_room {
+            return Err(SignallerError::ViewerNotFound);
+        }
+        if let Some(peer) = self.peers.get(target) {
+            send_message(&peer.sender, &SignallerMessage::Kicked {});
+            let _ = peer
+                .sender
+                .send(Message::close_with(CLOSE_KICKED, "kicked"));
+        }
+        info!("{} was kicked by {}", target, sharer);
+        self.cleanup_peer(target)
+    }
+
+    /// Let a viewer waiting for admission into the room of `sharer`.
+    pub fn admit(&self, sharer: &str, target: &str) -> Result<()> {
+        let room = self.sharer_room(sharer)?;
+        let sharers = {
+            let mut session = self
+                .sessions
+                .get_mut(&room)
+                .ok_or(SignallerError::RoomNotFound)?;
+            if !session.pending.contains(target) {
+                return Err(SignallerError::ViewerNotFound);
+            }
+            if session
+                .max_viewers
+                .is_some_and(|max| session.viewers.len() >= max)
+            {
+                return Err(SignallerError::RoomFull);
+            }
+            session.pending.remove(target);
+            session.viewers.insert(target.to_string());
+            session.peak_viewers = session.peak_viewers.max(session.viewers.len());
+            session.empty_since = None;
+            let sender = self.peers.get(target).map(|peer| peer.sender.clone());
+            if let Some(sender) = sender {
+                send_message(&sender, &SignallerMessage::Admitted {});
+                send_message(&sender, &self.presence(&session));
+            }
+            session.sharers().cloned().collect::<Vec<_>>()
+        };
+        info!("{} was admitted to room {} by {}", target, room, sharer);
+        for sharer in &sharers {
+            self.notify(
+                sharer,
+                &SignallerMessage::PeerJoined {
+                    peer: target.to_string(),
+                },
+            );
+        }
+        self.notify_viewer_count(&room);
+        Ok(())
+    }
+
+    /// Turn away a viewer waiting for admission into the room of `sharer`.
+    pub fn deny(&self, sharer: &str, target: &str) -> Result<()> {
+        let room = self.sharer_room(sharer)?;
+        if !self
+            .sessions
+            .get_mut(&room)
+            .ok_or(SignallerError::RoomNotFound)?
+            .pending
+            .remove(target)
+        {
+            return Err(SignallerError::ViewerNotFound);
+        }
+        info!(
+            "{} was denied admission to room {} by {}",
+            target, room, sharer
+        );
+        if let Some((_, peer)) = self.peers.remove_if(target, |_, peer| peer.room == room) {
+            let e = SignallerError::AdmissionDenied;
+            send_message(
+                &peer.sender,
+                &SignallerMessage::JoinDeclined {
+                    to: target.to_string(),
+                    reason: e.to_string(),
+                    code: e.code().to_string(),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Hand the sharer role over to one of the room's viewers, who swaps places with the sharer.
+    pub fn transfer_sharer(&self, sharer: &str, target: &str) -> Result<()> {
+        let room = self.sharer_room(sharer)?;
+        let (members, resume_token) = {
+            let mut session = self
+                .sessions
+                .get_mut(&room)
+                .ok_or(SignallerError::RoomNotFound)?;
+            if session.sharer != sharer {
+                return Err(SignallerError::NotSharer);
+            }
+            if !session.viewers.remove(target) {
+                return Err(SignallerError::ViewerNotFound);
+            }
+            session.viewers.insert(sharer.to_string());
+            session.sharer = target.to_string();
+            // The old sharer must not be able to reclaim the room with the token they hold.
+            session.resume_token = new_resume_token();
+            for (id, peer_type) in [(target, PeerType::Sharer {}), (sharer, PeerType::Viewer {})] {
+                if let Some(mut peer) = self.peers.get_mut(id) {
+                    peer.peer_type = peer_type;
+                }
+            }
+            (session.members(), session.resume_token.clone())
+        };
+        info!("{} handed room {} over to {}", sharer, room, target);
+        self.announce_sharer(&members, target, resume_token);
+        Ok(())
+    }
+
+    /// Tell everyone in a room who owns it now. Only the new owner gets the resume token.
+    fn announce_sharer(&self, members: &[String], sharer: &str, resume_token: String) {
+        for member in members {
+            self.notify(
+                member,
+                &SignallerMessage::SharerChanged {
+                    sharer: sharer.to_string(),
+                    resume_token: (member == sharer).then(|| resume_token.clone()),
+                },
+            );
+        }
+    }
+
+    /// Let one of the room's viewers present alongside the sharer.
+    pub fn promote_sharer(&self, sharer: &str, target: &str) -> Result<()> {
+        let room = self.sharer_room(sharer)?;
+        let members = {
+            let mut session = self
+                .sessions
+                .get_mut(&room)
+                .ok_or(SignallerError::RoomNotFound)?;
+            if !session.viewers.remove(target) {
+                return Err(SignallerError::ViewerNotFound);
+            }
+            if session.viewers.is_empty() {
+                session.empty_since = Some(Instant::now());
+            }
+            session.co_sharers.insert(target.to_string());
+            if let Some(mut peer) = self.peers.get_mut(target) {
+                peer.peer_type = PeerType::Sharer {};
+            }
+            session.members()
+        };
+        info!(
+            "{} promoted {} to co-sharer of room {}",
+            sharer, target, room
+        );
+        for member in &members {
+            self.notify(
+                member,
+                &SignallerMessage::SharerPromoted {
+                    peer: target.to_string(),
+                },
+            );
+        }
+        self.notify_viewer_count(&room);
+        Ok(())
+    }
+
+    /// What the server knows about the peer a connection registered as.
+    pub fn identity(&self, id: Option<&str>) -> SignallerMessage {
+        let peer = id.and_then(|id| {
+            self.peers.get(id).map(|peer| {
+                (
+                    id.to_string(),
+                    peer.room.clone(),
+                    peer.peer_type,
+                    peer.connected_secs(),
+                )
+            })
+        });
+        match peer {
+            Some((id, room, peer_type, connected_secs)) => SignallerMessage::Identity {
+                peer: Some(id),
+                room: Some(room),
+                role: match peer_type {
+                    PeerType::Sharer {} => "sharer",
+                    PeerType::Viewer {} => "viewer",
+                    PeerType::Spectator {} => "spectator",
+                }
+                .to_string(),
+                connected_secs: Some(connected_secs),
+            },
+            None => SignallerMessage::Identity {
+                peer: None,
+                room: None,
+                role: "unregistered".to_string(),
+                connected_secs: None,
+            },
+        }
+    }
+
+    pub fn list_rooms(&self) -> Vec<RoomInfo> {
+        let mut rooms: Vec<RoomInfo> = self
+            .sessions
+            .iter()
+            .map(|entry| RoomInfo {
+                room: entry.key().clone(),
+                viewers: entry.viewers.len(),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.room.cmp(&b.room));
+        rooms
+    }
+
+    /// The sessions whose resume token is `token`, or without a token the ones `id` shares.
+    pub fn my_sessions(&self, token: Option<&str>, id: Option<&str>) -> Vec<SessionInfo> {
+        let owned = |session: &Session| match (token, id) {
+            (Some(token), _) => session.resume_token == token,
+            (None, Some(id)) => session.sharers().any(|sharer| sharer == id),
+            (None, None) => false,
+        };
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .iter()
+            .filter(|entry| owned(entry.value()))
+            .map(|entry| SessionInfo {
+                room: entry.key().clone(),
+                name: entry.name.clone(),
+                viewers: entry.viewers.len(),
+                sharer_connected: entry.sharer_disconnected_at.is_none(),
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.room.cmp(&b.room));
+        sessions
+    }
+
+    /// How long to ask a client to wait before reconnecting, different for each client so they
+    /// spread out.
+    pub fn reconnect_after_ms(&self) -> u64 {
+        let jitter = thread_rng().gen_range(0..=self.reconnect_jitter.as_millis() as u64);
+        self.reconnect_delay.as_millis() as u64 + jitter
+    }
+
+    /// Tell every peer that the server is going away and close their connections.
+    pub fn shutdown(&self) {
+        for peer in self.with_namespaces().flat_map(|state| state.peers.iter()) {
+            send_message(
+                &peer.sender,
+                &SignallerMessage::ServerShutdown {
+                    reconnect_after_ms: self.reconnect_after_ms(),
+                },
+            );
+            let _ = peer.sender.send(Message::close_with(
+                CLOSE_TRY_AGAIN_LATER,
+                "server shutting down",
+            ));
+        }
+    }
+
     pub async fn get_ice_servers(&self) -> Vec<IceServer> {
+        let mut ice_servers = self.ice_servers.clone();
         if let (Some(client), Some(sid)) = (&self.twilio_client, &self.twilio_account_sid) {
-            get_twilio_ice_servers(client, sid).await
-        } else {
-            vec![]
+            ice_servers.extend(get_twilio_ice_servers(client, sid).await);
         }
+        ice_servers
     }
 }
+
+fn decline_join(viewer: &WaitingViewer, e: &SignallerError) {
+    info!("Error admitting {}: {}", viewer.id, e);
+    send_message(
+        &viewer.sender,
+        &SignallerMessage::JoinDeclined {
+            to: viewer.id.clone(),
+            reason: e.to_string(),
+            code: e.code().to_string(),
+        },
+    );
+}
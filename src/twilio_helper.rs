@@ -1,5 +1,5 @@
-use log::error;
 use serde_json::Value;
+use tracing::error;
 
 use crate::signaller_message::IceServer;
 
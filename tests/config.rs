@@ -0,0 +1,38 @@
+//! Loads options from `--config` files, the way the binary does at startup.
+
+use std::path::{Path, PathBuf};
+
+use signaller::args::Args;
+
+/// Write `contents` to a config file unique to `name`.
+fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("signaller-{}-{name}.toml", std::process::id()));
+    std::fs::write(&path, contents).expect("config can be written");
+    path
+}
+
+fn load(config: &Path) -> anyhow::Result<Args> {
+    Args::load_from([
+        "signaller".as_ref(),
+        "--ip-hash-salt".as_ref(),
+        "dGVzdHNhbHQ".as_ref(),
+        "--config".as_ref(),
+        config.as_os_str(),
+    ])
+}
+
+#[test]
+fn switches_can_be_turned_on_and_off() {
+    let on = load(&config_file("switch-on", "reuse_port = true\n")).expect("config loads");
+    assert!(on.reuse_port());
+
+    let off = load(&config_file("switch-off", "reuse_port = false\n")).expect("config loads");
+    assert!(!off.reuse_port());
+}
+
+#[test]
+fn switches_only_take_booleans() {
+    let e = load(&config_file("switch-string", "reuse_port = \"yes\"\n"))
+        .expect_err("a string doesn't turn on a switch");
+    assert!(e.to_string().contains("must be true or false"), "{e}");
+}
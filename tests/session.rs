@@ -0,0 +1,977 @@
+//! Runs the server on an ephemeral port and drives a session through real websocket clients.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use clap::Parser;
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use signaller::args::Args;
+use signaller::config::Config;
+use signaller::Server;
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How long to wait for a message before failing the test.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn start_server() -> SocketAddr {
+    start_server_with(&[]).await
+}
+
+/// Like `start_server`, with extra command line arguments.
+async fn start_server_with(extra_args: &[&str]) -> SocketAddr {
+    let args = Args::parse_from(
+        ["signaller", "--ip-hash-salt", "dGVzdHNhbHQ"]
+            .iter()
+            .chain(extra_args),
+    );
+    let config = Config {
+        twilio_account_sid: None,
+        twilio_auth_token: None,
+        ice_servers: vec![],
+    };
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("listener can be bound");
+    let addr = listener.local_addr().expect("listener has an address");
+    tokio::spawn(Server::new(args, config).run(listener));
+    addr
+}
+
+async fn connect(addr: SocketAddr) -> Client {
+    connect_to(addr, "/").await
+}
+
+async fn connect_to(addr: SocketAddr, path: &str) -> Client {
+    let (client, _) = tokio_tungstenite::connect_async(format!("ws://{addr}{path}"))
+        .await
+        .expect("client can connect");
+    client
+}
+
+async fn send(client: &mut Client, msg: Value) {
+    client
+        .send(Message::Text(msg.to_string()))
+        .await
+        .expect("message can be sent");
+}
+
+/// The next message of type `kind`, skipping any others such as ICE server lists and presence.
+async fn recv(client: &mut Client, kind: &str) -> Value {
+    serde_json::from_str(&recv_text(client, kind).await).expect("message is JSON")
+}
+
+/// Like `recv`, but the frame exactly as it arrived.
+async fn recv_text(client: &mut Client, kind: &str) -> String {
+    loop {
+        let frame = tokio::time::timeout(RECV_TIMEOUT, client.next())
+            .await
+            .unwrap_or_else(|_| panic!("timed out waiting for {kind}"))
+            .expect("connection is open")
+            .expect("frame can be read");
+        let Message::Text(text) = frame else {
+            continue;
+        };
+        let msg: Value = serde_json::from_str(&text).expect("message is JSON");
+        if msg["type"] == kind {
+            return text;
+        }
+    }
+}
+
+/// Start a room and join it, returning the sharer, the viewer and the room id.
+async fn start_session(addr: SocketAddr) -> (Client, Client, String) {
+    let mut sharer = connect(addr).await;
+    send(&mut sharer, json!({"type": "start"})).await;
+    let room = recv(&mut sharer, "start_response").await["room"]
+        .as_str()
+        .expect("room is a string")
+        .to_string();
+
+    let viewer = join(addr, &mut sharer, &room, "viewer").await;
+    (sharer, viewer, room)
+}
+
+/// Join `room` as viewer `id`, waiting until its sharer has seen them arrive.
+async fn join(addr: SocketAddr, sharer: &mut Client, room: &str, id: &str) -> Client {
+    let mut viewer = connect(addr).await;
+    send(
+        &mut viewer,
+        json!({"type": "join", "from": id, "room": room}),
+    )
+    .await;
+    recv(sharer, "peer_joined").await;
+    viewer
+}
+
+/// Fail if a message of type `kind` arrives within a short while.
+async fn assert_no_message(client: &mut Client, kind: &str) {
+    let waited = tokio::time::timeout(Duration::from_millis(200), recv(client, kind)).await;
+    assert!(waited.is_err(), "unexpected {kind}: {:?}", waited);
+}
+
+#[tokio::test]
+async fn forwards_offer_answer_and_ice() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    let sdp = json!({"type": "offer", "sdp": "v=0"});
+    send(
+        &mut sharer,
+        json!({"type": "offer", "from": room, "to": "viewer", "sdp": sdp}),
+    )
+    .await;
+    let offer = recv(&mut viewer, "offer").await;
+    assert_eq!(offer["from"], room.as_str());
+    assert_eq!(offer["sdp"], sdp);
+
+    let sdp = json!({"type": "answer", "sdp": "v=0"});
+    send(
+        &mut viewer,
+        json!({"type": "answer", "from": "viewer", "to": room, "sdp": sdp}),
+    )
+    .await;
+    let answer = recv(&mut sharer, "answer").await;
+    assert_eq!(answer["from"], "viewer");
+    assert_eq!(answer["sdp"], sdp);
+
+    let candidate = json!({"candidate": "candidate:1 1 udp 1 127.0.0.1 9 typ host", "sdpMid": "0"});
+    send(
+        &mut viewer,
+        json!({"type": "ice", "from": "viewer", "to": room, "candidate": candidate}),
+    )
+    .await;
+    let ice = recv(&mut sharer, "ice").await;
+    assert_eq!(ice["from"], "viewer");
+    assert_eq!(ice["candidate"]["candidate"], candidate["candidate"]);
+}
+
+#[tokio::test]
+async fn sharer_leaving_closes_the_room() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    send(&mut sharer, json!({"type": "leave", "from": room})).await;
+    let closed = recv(&mut viewer, "room_closed").await;
+    assert_eq!(closed["to"], "viewer");
+    assert_eq!(closed["room"], room.as_str());
+}
+
+#[tokio::test]
+async fn offer_without_recipient_reaches_every_viewer() {
+    let addr = start_server().await;
+    let (mut sharer, mut first, room) = start_session(addr).await;
+    let mut second = join(addr, &mut sharer, &room, "second").await;
+
+    let sdp = json!({"type": "offer", "sdp": "v=0"});
+    send(
+        &mut sharer,
+        json!({"type": "offer", "from": room, "sdp": sdp}),
+    )
+    .await;
+    for viewer in [&mut first, &mut second] {
+        let offer = recv(viewer, "offer").await;
+        assert_eq!(offer["from"], room.as_str());
+        assert_eq!(offer["sdp"], sdp);
+    }
+}
+
+#[tokio::test]
+async fn offer_with_recipient_reaches_only_that_viewer() {
+    let addr = start_server().await;
+    let (mut sharer, mut first, room) = start_session(addr).await;
+    let mut second = join(addr, &mut sharer, &room, "second").await;
+
+    send(
+        &mut sharer,
+        json!({"type": "offer", "from": room, "to": "second", "sdp": {}}),
+    )
+    .await;
+    assert_eq!(recv(&mut second, "offer").await["to"], "second");
+    assert_no_message(&mut first, "offer").await;
+}
+
+#[tokio::test]
+async fn answer_from_viewer_reaches_the_sharer() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    send(
+        &mut viewer,
+        json!({"type": "answer", "from": "viewer", "to": room, "sdp": {}, "seq": 1}),
+    )
+    .await;
+    assert_eq!(recv(&mut sharer, "answer").await["from"], "viewer");
+    assert_eq!(recv(&mut viewer, "ack").await["seq"], 1);
+}
+
+#[tokio::test]
+async fn answer_to_another_viewer_is_rejected() {
+    let addr = start_server().await;
+    let (mut sharer, mut first, room) = start_session(addr).await;
+    let mut second = join(addr, &mut sharer, &room, "second").await;
+
+    send(
+        &mut first,
+        json!({"type": "answer", "from": "viewer", "to": "second", "sdp": {}}),
+    )
+    .await;
+    assert_eq!(recv(&mut first, "error").await["code"], "bad_route");
+    assert_no_message(&mut second, "answer").await;
+}
+
+#[tokio::test]
+async fn data_answers_follow_the_answer_route() {
+    let addr = start_server().await;
+    let (mut sharer, mut first, room) = start_session(addr).await;
+    let mut second = join(addr, &mut sharer, &room, "second").await;
+
+    send(
+        &mut first,
+        json!({"type": "data_answer", "from": "viewer", "to": "second", "sdp": {}}),
+    )
+    .await;
+    assert_eq!(recv(&mut first, "error").await["code"], "bad_route");
+    assert_no_message(&mut second, "data_answer").await;
+
+    send(
+        &mut sharer,
+        json!({"type": "data_answer", "from": room, "sdp": {}}),
+    )
+    .await;
+    assert_eq!(recv(&mut sharer, "error").await["code"], "bad_route");
+    assert_no_message(&mut first, "data_answer").await;
+
+    send(
+        &mut first,
+        json!({"type": "data_answer", "from": "viewer", "to": room, "sdp": {}}),
+    )
+    .await;
+    assert_eq!(recv(&mut sharer, "data_answer").await["from"], "viewer");
+}
+
+#[tokio::test]
+async fn ice_candidates_arrive_in_order() {
+    const COUNT: usize = 1000;
+    // Room for every candidate, so none are dropped by the rate limit or a full queue.
+    let addr = start_server_with(&["--message-burst", "2000", "--send-queue-size", "2000"]).await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    for i in 0..COUNT {
+        let candidate = json!({"candidate": format!("candidate:{i} 1 udp 1 127.0.0.1 9 typ host")});
+        send(
+            &mut sharer,
+            json!({"type": "ice", "from": room, "to": "viewer", "candidate": candidate}),
+        )
+        .await;
+    }
+    for i in 0..COUNT {
+        let ice = recv(&mut viewer, "ice").await;
+        assert_eq!(
+            ice["candidate"]["candidate"],
+            format!("candidate:{i} 1 udp 1 127.0.0.1 9 typ host")
+        );
+    }
+}
+
+#[tokio::test]
+async fn messages_for_a_dropped_sharer_are_delivered_on_resume() {
+    let addr = start_server().await;
+    let mut sharer = connect(addr).await;
+    send(&mut sharer, json!({"type": "start"})).await;
+    let started = recv(&mut sharer, "start_response").await;
+    let room = started["room"]
+        .as_str()
+        .expect("room is a string")
+        .to_string();
+    let token = started["resume_token"].clone();
+    let mut viewer = join(addr, &mut sharer, &room, "viewer").await;
+
+    drop(sharer);
+    // Wait for the server to notice, otherwise the answer goes to the old connection.
+    loop {
+        send(&mut viewer, json!({"type": "my_sessions", "token": token})).await;
+        let sessions = recv(&mut viewer, "session_list").await;
+        if sessions["sessions"][0]["sharer_connected"] == false {
+            break;
+        }
+    }
+    send(
+        &mut viewer,
+        json!({"type": "answer", "from": "viewer", "to": room, "sdp": {}, "seq": 1}),
+    )
+    .await;
+    assert_eq!(recv(&mut viewer, "ack").await["seq"], 1);
+
+    let mut resumed = connect(addr).await;
+    send(
+        &mut resumed,
+        json!({"type": "resume", "room": room, "token": token}),
+    )
+    .await;
+    assert_eq!(recv(&mut resumed, "answer").await["from"], "viewer");
+    recv(&mut resumed, "resumed").await;
+}
+
+#[tokio::test]
+async fn sharer_cannot_join_its_own_room() {
+    let addr = start_server().await;
+    let (_sharer, _viewer, room) = start_session(addr).await;
+
+    // The sharer's own connection is already registered, so this comes in on another one.
+    let mut impostor = connect(addr).await;
+    send(
+        &mut impostor,
+        json!({"type": "join", "from": room, "room": room}),
+    )
+    .await;
+    let declined = recv(&mut impostor, "join_declined").await;
+    assert_eq!(declined["code"], "already_sharer");
+}
+
+#[tokio::test]
+async fn namespaces_do_not_share_rooms() {
+    let addr = start_server_with(&["--namespaces", "first,second"]).await;
+    let mut sharer = connect_to(addr, "/ns/first").await;
+    send(&mut sharer, json!({"type": "start"})).await;
+    let room = recv(&mut sharer, "start_response").await["room"].clone();
+
+    for path in ["/", "/ns/second"] {
+        let mut outsider = connect_to(addr, path).await;
+        send(
+            &mut outsider,
+            json!({"type": "join", "from": "outsider", "room": room}),
+        )
+        .await;
+        let declined = recv(&mut outsider, "join_declined").await;
+        assert_eq!(declined["code"], "room_not_found");
+    }
+
+    let mut viewer = connect_to(addr, "/ns/first").await;
+    send(
+        &mut viewer,
+        json!({"type": "join", "from": "viewer", "room": room}),
+    )
+    .await;
+    assert_eq!(recv(&mut sharer, "peer_joined").await["peer"], "viewer");
+
+    let unknown = tokio_tungstenite::connect_async(format!("ws://{addr}/ns/third")).await;
+    assert!(unknown.is_err(), "unknown namespace was accepted");
+}
+
+#[tokio::test]
+async fn connections_beyond_max_accepted_wait_for_a_free_slot() {
+    let addr = start_server_with(&["--max-accepted", "1"]).await;
+    let mut first = connect(addr).await;
+
+    let second = tokio::spawn(connect(addr));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!second.is_finished(), "second connection was served early");
+
+    first.close(None).await.expect("client can close");
+    let mut second = tokio::time::timeout(Duration::from_secs(5), second)
+        .await
+        .expect("second connection is served once the first closes")
+        .expect("connect task doesn't panic");
+    send(&mut second, json!({"type": "start"})).await;
+    recv(&mut second, "start_response").await;
+}
+
+#[tokio::test]
+async fn quality_requests_reach_the_sharer() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, _room) = start_session(addr).await;
+
+    send(
+        &mut viewer,
+        json!({"type": "quality_request", "level": "low"}),
+    )
+    .await;
+    let request = recv(&mut sharer, "quality_request_from").await;
+    assert_eq!(request["from"], "viewer");
+    assert_eq!(request["level"], "low");
+
+    send(
+        &mut viewer,
+        json!({"type": "quality_request", "level": "ultra"}),
+    )
+    .await;
+    let error = recv(&mut viewer, "error").await;
+    assert_eq!(error["code"], "unknown_quality_level");
+    assert_no_message(&mut sharer, "quality_request_from").await;
+
+    send(
+        &mut sharer,
+        json!({"type": "quality_request", "level": "high"}),
+    )
+    .await;
+    assert_eq!(recv(&mut sharer, "error").await["code"], "not_viewer");
+}
+
+#[tokio::test]
+async fn only_the_peer_itself_can_leave() {
+    let addr = start_server().await;
+    let (_sharer, mut viewer, room) = start_session(addr).await;
+
+    let mut outsider = connect(addr).await;
+    send(&mut outsider, json!({"type": "leave", "from": room})).await;
+    assert_eq!(
+        recv(&mut outsider, "error").await["code"],
+        "sender_mismatch"
+    );
+    assert_no_message(&mut viewer, "room_closed").await;
+}
+
+#[tokio::test]
+async fn signalling_within_a_room_is_forwarded() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    send(
+        &mut viewer,
+        json!({"type": "ice", "from": "viewer", "to": room, "candidate": {"candidate": "candidate:1 1 udp 1 127.0.0.1 9 typ host"}}),
+    )
+    .await;
+    assert_eq!(recv(&mut sharer, "ice").await["from"], "viewer");
+}
+
+#[tokio::test]
+async fn signalling_across_rooms_is_rejected() {
+    let addr = start_server().await;
+    let (_first_sharer, mut first_viewer, _) = start_session(addr).await;
+    let mut second_sharer = connect(addr).await;
+    send(&mut second_sharer, json!({"type": "start"})).await;
+    let second_room = recv(&mut second_sharer, "start_response").await["room"].clone();
+
+    send(
+        &mut first_viewer,
+        json!({"type": "ice", "from": "viewer", "to": second_room, "candidate": {"candidate": "candidate:1 1 udp 1 127.0.0.1 9 typ host"}}),
+    )
+    .await;
+    assert_eq!(
+        recv(&mut first_viewer, "error").await["code"],
+        "not_in_room"
+    );
+    assert_no_message(&mut second_sharer, "ice").await;
+}
+
+#[tokio::test]
+async fn clients_cannot_send_server_notices_to_peers() {
+    let addr = start_server().await;
+    let (_sharer, mut viewer, room) = start_session(addr).await;
+
+    let mut outsider = connect(addr).await;
+    send(
+        &mut outsider,
+        json!({"type": "room_closed", "to": "viewer", "room": room}),
+    )
+    .await;
+    send(
+        &mut outsider,
+        json!({"type": "join_declined", "to": "viewer", "reason": "bye", "code": "room_full"}),
+    )
+    .await;
+    assert_no_message(&mut viewer, "room_closed").await;
+    assert_no_message(&mut viewer, "join_declined").await;
+}
+
+#[tokio::test]
+async fn resuming_a_handed_over_room_restores_the_new_sharer() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    send(
+        &mut sharer,
+        json!({"type": "transfer_sharer", "target": "viewer"}),
+    )
+    .await;
+    let token = recv(&mut viewer, "sharer_changed").await["resume_token"].clone();
+    drop(viewer);
+    loop {
+        send(&mut sharer, json!({"type": "my_sessions", "token": token})).await;
+        let sessions = recv(&mut sharer, "session_list").await;
+        if sessions["sessions"][0]["sharer_connected"] == false {
+            break;
+        }
+    }
+
+    let mut resumed = connect(addr).await;
+    send(
+        &mut resumed,
+        json!({"type": "resume", "room": room, "token": token}),
+    )
+    .await;
+    recv(&mut resumed, "resumed").await;
+    send(&mut resumed, json!({"type": "who_am_i"})).await;
+    let identity = recv(&mut resumed, "identity").await;
+    assert_eq!(identity["peer"], "viewer");
+    assert_eq!(identity["role"], "sharer");
+}
+
+#[tokio::test]
+async fn a_connection_registers_only_once() {
+    let addr = start_server().await;
+    let (mut sharer, _viewer, room) = start_session(addr).await;
+
+    send(&mut sharer, json!({"type": "start"})).await;
+    assert_eq!(
+        recv(&mut sharer, "error").await["code"],
+        "already_registered"
+    );
+    send(
+        &mut sharer,
+        json!({"type": "join", "from": "other", "room": room}),
+    )
+    .await;
+    assert_eq!(
+        recv(&mut sharer, "join_declined").await["code"],
+        "already_registered"
+    );
+
+    send(&mut sharer, json!({"type": "list_rooms"})).await;
+    let rooms = recv(&mut sharer, "room_list").await;
+    assert_eq!(rooms["rooms"].as_array().map(Vec::len), Some(1));
+}
+
+/// Open a websocket claiming to be forwarded for `forwarded_for`, returning whether the
+/// handshake succeeded.
+async fn connect_forwarded_for(addr: SocketAddr, forwarded_for: &str) -> bool {
+    let mut request = format!("ws://{addr}/")
+        .into_client_request()
+        .expect("request is valid");
+    request.headers_mut().insert(
+        "x-forwarded-for",
+        forwarded_for.parse().expect("header is valid"),
+    );
+    tokio_tungstenite::connect_async(request).await.is_ok()
+}
+
+#[tokio::test]
+async fn forwarded_for_is_ignored_from_untrusted_peers() {
+    let addr = start_server_with(&["--connection-burst", "1"]).await;
+    assert!(connect_forwarded_for(addr, "192.0.2.1").await);
+    assert!(!connect_forwarded_for(addr, "192.0.2.2").await);
+}
+
+#[tokio::test]
+async fn forwarded_for_is_used_from_trusted_proxies() {
+    let addr =
+        start_server_with(&["--connection-burst", "1", "--trusted-proxies", "127.0.0.1"]).await;
+    assert!(connect_forwarded_for(addr, "192.0.2.1").await);
+    assert!(connect_forwarded_for(addr, "192.0.2.2").await);
+    assert!(!connect_forwarded_for(addr, "192.0.2.2").await);
+}
+
+#[tokio::test]
+async fn trusted_proxies_can_be_given_as_ranges() {
+    let addr = start_server_with(&[
+        "--connection-burst",
+        "1",
+        "--trusted-proxies",
+        "127.0.0.0/8",
+    ])
+    .await;
+    assert!(connect_forwarded_for(addr, "192.0.2.1").await);
+    assert!(connect_forwarded_for(addr, "192.0.2.2").await);
+    assert!(!connect_forwarded_for(addr, "192.0.2.2").await);
+}
+
+#[tokio::test]
+async fn protected_rooms_check_the_password() {
+    let addr = start_server_with(&["--join-grace", "5"]).await;
+    let mut early = connect(addr).await;
+    send(
+        &mut early,
+        json!({"type": "join", "from": "early", "room": "lobby", "password": "secret"}),
+    )
+    .await;
+    recv(&mut early, "join_pending").await;
+
+    let mut sharer = connect(addr).await;
+    send(
+        &mut sharer,
+        json!({"type": "start", "name": "lobby", "password": "secret"}),
+    )
+    .await;
+    recv(&mut sharer, "start_response").await;
+    // Waiting viewers are checked against the password once the room starts.
+    assert_eq!(recv(&mut sharer, "peer_joined").await["peer"], "early");
+    let presence = recv(&mut early, "presence").await;
+    assert_eq!(presence["viewers"], json!(["early"]));
+
+    let mut wrong = connect(addr).await;
+    send(
+        &mut wrong,
+        json!({"type": "join", "from": "wrong", "room": "lobby", "password": "guess"}),
+    )
+    .await;
+    let declined = recv(&mut wrong, "join_declined").await;
+    assert_eq!(declined["code"], "invalid_password");
+
+    let mut right = connect(addr).await;
+    send(
+        &mut right,
+        json!({"type": "join", "from": "right", "room": "lobby", "password": "secret"}),
+    )
+    .await;
+    assert_eq!(recv(&mut sharer, "peer_joined").await["peer"], "right");
+}
+
+#[tokio::test]
+async fn sharer_hears_of_its_room_before_early_viewers() {
+    let addr = start_server_with(&["--join-grace", "5"]).await;
+    let mut early = connect(addr).await;
+    send(
+        &mut early,
+        json!({"type": "join", "from": "early", "room": "lobby"}),
+    )
+    .await;
+    recv(&mut early, "join_pending").await;
+
+    let mut sharer = connect(addr).await;
+    send(&mut sharer, json!({"type": "start", "name": "lobby"})).await;
+    let mut kinds = Vec::new();
+    while kinds.last().map(String::as_str) != Some("peer_joined") {
+        let frame = tokio::time::timeout(RECV_TIMEOUT, sharer.next())
+            .await
+            .expect("viewer is admitted in time")
+            .expect("connection is open")
+            .expect("frame can be read");
+        if let Message::Text(text) = frame {
+            let msg: Value = serde_json::from_str(&text).expect("message is JSON");
+            kinds.push(msg["type"].as_str().expect("type is a string").to_string());
+        }
+    }
+    assert_eq!(
+        kinds,
+        ["start_response", "ice_servers_response", "peer_joined"]
+    );
+}
+
+#[tokio::test]
+async fn repeated_wrong_passwords_close_the_connection() {
+    let addr = start_server().await;
+    let mut sharer = connect(addr).await;
+    send(
+        &mut sharer,
+        json!({"type": "start", "name": "lobby", "password": "secret"}),
+    )
+    .await;
+    recv(&mut sharer, "start_response").await;
+
+    let mut guesser = connect(addr).await;
+    for i in 0..5 {
+        send(
+            &mut guesser,
+            json!({"type": "join", "from": "guesser", "room": "lobby", "password": format!("guess{i}")}),
+        )
+        .await;
+        let declined = recv(&mut guesser, "join_declined").await;
+        assert_eq!(declined["code"], "invalid_password");
+    }
+    let close = loop {
+        let frame = tokio::time::timeout(RECV_TIMEOUT, guesser.next())
+            .await
+            .expect("connection is closed in time")
+            .expect("connection is open")
+            .expect("frame can be read");
+        if let Message::Close(close) = frame {
+            break close.expect("close frame has a code");
+        }
+    };
+    assert_eq!(u16::from(close.code), 4003);
+}
+
+#[tokio::test]
+async fn viewer_cannot_take_the_id_of_another_rooms_sharer() {
+    let addr = start_server().await;
+    let (_first_sharer, _viewer, first_room) = start_session(addr).await;
+    let mut second_sharer = connect(addr).await;
+    send(&mut second_sharer, json!({"type": "start"})).await;
+    let second_room = recv(&mut second_sharer, "start_response").await["room"].clone();
+
+    let mut impostor = connect(addr).await;
+    send(
+        &mut impostor,
+        json!({"type": "join", "from": first_room, "room": second_room}),
+    )
+    .await;
+    let declined = recv(&mut impostor, "join_declined").await;
+    assert_eq!(declined["code"], "peer_exists");
+}
+
+#[tokio::test]
+async fn peer_ids_cannot_be_registered_twice() {
+    let addr = start_server().await;
+    let (_sharer, _viewer, room) = start_session(addr).await;
+
+    let mut duplicate = connect(addr).await;
+    send(
+        &mut duplicate,
+        json!({"type": "join", "from": "viewer", "room": room}),
+    )
+    .await;
+    let declined = recv(&mut duplicate, "join_declined").await;
+    assert_eq!(declined["code"], "peer_exists");
+}
+
+#[tokio::test]
+async fn list_rooms_on_an_empty_server() {
+    let addr = start_server().await;
+    let mut client = connect(addr).await;
+
+    send(&mut client, json!({"type": "list_rooms"})).await;
+    assert_eq!(recv(&mut client, "room_list").await["rooms"], json!([]));
+}
+
+#[tokio::test]
+async fn list_rooms_shows_each_room_with_its_viewers() {
+    let addr = start_server().await;
+    let (_first_sharer, _viewer, first_room) = start_session(addr).await;
+    let mut second_sharer = connect(addr).await;
+    send(&mut second_sharer, json!({"type": "start"})).await;
+    let second_room = recv(&mut second_sharer, "start_response").await["room"].clone();
+
+    let mut client = connect(addr).await;
+    send(&mut client, json!({"type": "list_rooms"})).await;
+    let mut rooms = recv(&mut client, "room_list").await["rooms"]
+        .as_array()
+        .expect("rooms is a list")
+        .clone();
+    rooms.sort_by_key(|room| room["viewers"].as_u64());
+    assert_eq!(
+        rooms,
+        vec![
+            json!({"room": second_room, "viewers": 0}),
+            json!({"room": first_room, "viewers": 1}),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn sharer_lists_its_viewers() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+    let _second = join(addr, &mut sharer, &room, "second").await;
+    let _third = join(addr, &mut sharer, &room, "third").await;
+
+    send(&mut sharer, json!({"type": "list_viewers"})).await;
+    let list = recv(&mut sharer, "viewer_list").await;
+    assert_eq!(list["viewers"], json!(["second", "third", "viewer"]));
+
+    send(&mut viewer, json!({"type": "list_viewers"})).await;
+    assert_eq!(recv(&mut viewer, "error").await["code"], "not_sharer");
+    let mut unregistered = connect(addr).await;
+    send(&mut unregistered, json!({"type": "list_viewers"})).await;
+    assert_eq!(
+        recv(&mut unregistered, "error").await["code"],
+        "not_registered"
+    );
+}
+
+#[tokio::test]
+async fn app_ping_is_echoed_to_its_sender() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, _room) = start_session(addr).await;
+
+    send(&mut viewer, json!({"type": "app_ping", "nonce": "n-42"})).await;
+    assert_eq!(recv(&mut viewer, "app_pong").await["nonce"], "n-42");
+    assert_no_message(&mut sharer, "app_pong").await;
+}
+
+#[tokio::test]
+async fn self_addressed_ice_is_rejected() {
+    let addr = start_server().await;
+    let (_sharer, mut viewer, _room) = start_session(addr).await;
+
+    send(
+        &mut viewer,
+        json!({
+            "type": "ice",
+            "from": "viewer",
+            "to": "viewer",
+            "candidate": {"candidate": "candidate:1 1 udp 1 127.0.0.1 9 typ host"},
+        }),
+    )
+    .await;
+    assert_eq!(recv(&mut viewer, "error").await["code"], "self_addressed");
+    assert_no_message(&mut viewer, "ice").await;
+}
+
+#[tokio::test]
+async fn offer_sdp_is_delivered_verbatim() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    let sdp = [
+        "v=0",
+        "o=- 4611731400430051336 2 IN IP4 127.0.0.1",
+        "s=-",
+        "t=0 0",
+        "a=group:BUNDLE 0",
+        "a=msid-semantic: WMS stream",
+        "m=video 9 UDP/TLS/RTP/SAVPF 96 97",
+        "c=IN IP4 0.0.0.0",
+        "a=rtcp:9 IN IP4 0.0.0.0",
+        "a=ice-ufrag:EsAw",
+        "a=ice-pwd:bP+XJMM09aR8AiX1jdukzR6Y",
+        "a=fingerprint:sha-256 D2:FA:0E:C3:22:59:5E:14:95:69:92:3D:13:B4:84:24:2C:C2:A2:C0",
+        "a=setup:actpass",
+        "a=mid:0",
+        "a=sendonly",
+        "a=rtpmap:96 VP8/90000",
+        "a=rtpmap:97 rtx/90000",
+        "a=fmtp:97 apt=96",
+        "",
+    ]
+    .join("\r\n");
+    // Keys in an order serde wouldn't produce, so a re-encoded frame would differ.
+    let frame = format!(
+        r#"{{"sdp":{{"type":"offer","sdp":{}}},"to":"viewer","type":"offer","from":"{room}"}}"#,
+        Value::String(sdp)
+    );
+    sharer
+        .send(Message::Text(frame.clone()))
+        .await
+        .expect("message can be sent");
+    assert_eq!(recv_text(&mut viewer, "offer").await, frame);
+}
+
+#[tokio::test]
+async fn ice_candidate_is_delivered_byte_for_byte() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    let frame = format!(
+        r#"{{"candidate":{{"sdpMLineIndex":0,"sdpMid":"0","candidate":"candidate:842163049 1 udp 1677729535 203.0.113.7 46154 typ srflx raddr 0.0.0.0 rport 0 generation 0"}},"from":"viewer","type":"ice","to":"{room}"}}"#
+    );
+    viewer
+        .send(Message::Text(frame.clone()))
+        .await
+        .expect("message can be sent");
+    assert_eq!(recv_text(&mut sharer, "ice").await, frame);
+}
+
+#[tokio::test]
+async fn empty_ice_candidate_is_rejected() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+
+    send(
+        &mut viewer,
+        json!({"type": "ice", "from": "viewer", "to": room, "candidate": {"candidate": ""}}),
+    )
+    .await;
+    assert_eq!(recv(&mut viewer, "error").await["code"], "invalid_message");
+    assert_no_message(&mut sharer, "ice").await;
+}
+
+#[tokio::test]
+async fn viewers_cannot_ask_for_renegotiation() {
+    let addr = start_server().await;
+    let (_sharer, mut viewer, _room) = start_session(addr).await;
+
+    send(&mut viewer, json!({"type": "renegotiate"})).await;
+    let error = recv(&mut viewer, "error").await;
+    assert_eq!(error["code"], "forbidden");
+    assert_eq!(
+        error["message"],
+        "only sharers can ask the room to renegotiate"
+    );
+}
+
+#[tokio::test]
+async fn unknown_types_are_told_apart_from_malformed_messages() {
+    let addr = start_server().await;
+    let mut client = connect(addr).await;
+
+    send(&mut client, json!({"type": "teleport"})).await;
+    assert_eq!(
+        recv(&mut client, "error").await["code"],
+        "unknown_message_type"
+    );
+    send(&mut client, json!({"type": "app_ping"})).await;
+    assert_eq!(recv(&mut client, "error").await["code"], "invalid_message");
+}
+
+#[tokio::test]
+async fn viewer_can_join_again_after_its_room_closed() {
+    let addr = start_server().await;
+    let (mut sharer, mut viewer, room) = start_session(addr).await;
+    send(&mut sharer, json!({"type": "leave", "from": room})).await;
+    recv(&mut viewer, "room_closed").await;
+
+    let mut next_sharer = connect(addr).await;
+    send(&mut next_sharer, json!({"type": "start"})).await;
+    let next_room = recv(&mut next_sharer, "start_response").await["room"].clone();
+    send(
+        &mut viewer,
+        json!({"type": "join", "from": "viewer", "room": next_room}),
+    )
+    .await;
+    assert_eq!(
+        recv(&mut next_sharer, "peer_joined").await["peer"],
+        "viewer"
+    );
+    send(&mut viewer, json!({"type": "who_am_i"})).await;
+    assert_eq!(recv(&mut viewer, "identity").await["role"], "viewer");
+}
+
+#[tokio::test]
+async fn viewer_can_ask_again_after_being_denied() {
+    let addr = start_server().await;
+    let mut sharer = connect(addr).await;
+    send(
+        &mut sharer,
+        json!({"type": "start", "require_admission": true}),
+    )
+    .await;
+    let room = recv(&mut sharer, "start_response").await["room"].clone();
+
+    let mut viewer = connect(addr).await;
+    let join = json!({"type": "join", "from": "viewer", "room": room});
+    send(&mut viewer, join.clone()).await;
+    assert_eq!(
+        recv(&mut sharer, "admission_request").await["peer"],
+        "viewer"
+    );
+    send(&mut sharer, json!({"type": "deny", "target": "viewer"})).await;
+    let declined = recv(&mut viewer, "join_declined").await;
+    assert_eq!(declined["code"], "admission_denied");
+
+    send(&mut viewer, join).await;
+    recv(&mut viewer, "join_pending").await;
+    assert_eq!(
+        recv(&mut sharer, "admission_request").await["peer"],
+        "viewer"
+    );
+}
+
+#[tokio::test]
+async fn stale_connection_cannot_act_for_the_next_owner_of_its_id() {
+    let addr = start_server().await;
+    let (mut sharer, mut stale, room) = start_session(addr).await;
+    send(&mut sharer, json!({"type": "leave", "from": room})).await;
+    recv(&mut stale, "room_closed").await;
+
+    let (mut next_sharer, _viewer, next_room) = start_session(addr).await;
+    send(
+        &mut stale,
+        json!({"type": "offer", "from": "viewer", "to": next_room, "sdp": {}}),
+    )
+    .await;
+    assert_eq!(recv(&mut stale, "error").await["code"], "sender_mismatch");
+    assert_no_message(&mut next_sharer, "offer").await;
+
+    drop(stale);
+    assert_no_message(&mut next_sharer, "leave").await;
+    send(&mut next_sharer, json!({"type": "list_viewers"})).await;
+    let list = recv(&mut next_sharer, "viewer_list").await;
+    assert_eq!(list["viewers"], json!(["viewer"]));
+}
@@ -0,0 +1,75 @@
+//! Starting the server with TLS settings it can't use.
+
+use clap::Parser;
+
+use signaller::args::Args;
+use signaller::config::Config;
+use signaller::Server;
+
+/// Start serving with `extra_args` on top of a TLS setup, returning the startup error.
+async fn serve_error(extra_args: &[&str]) -> String {
+    let args = Args::parse_from(
+        ["signaller", "--ip-hash-salt", "dGVzdHNhbHQ"]
+            .iter()
+            .chain(extra_args),
+    );
+    let config = Config {
+        twilio_account_sid: None,
+        twilio_auth_token: None,
+        ice_servers: vec![],
+    };
+    let e = Server::new(args, config)
+        .serve()
+        .await
+        .expect_err("server doesn't start");
+    format!("{e:#}")
+}
+
+#[tokio::test]
+async fn missing_certificate_is_a_startup_error() {
+    let e = serve_error(&[
+        "--bind",
+        "127.0.0.1:0",
+        "--cert",
+        "/nonexistent/cert.pem",
+        "--key",
+        "/nonexistent/key.pem",
+    ])
+    .await;
+    assert!(e.contains("/nonexistent/cert.pem"), "{e}");
+}
+
+#[tokio::test]
+async fn missing_client_ca_is_a_startup_error() {
+    let e = serve_error(&[
+        "--bind",
+        "127.0.0.1:0",
+        "--cert",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/server.crt"),
+        "--key",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/server.key"),
+        "--tls-client-ca",
+        "/nonexistent/ca.pem",
+    ])
+    .await;
+    assert!(e.contains("/nonexistent/ca.pem"), "{e}");
+}
+
+#[tokio::test]
+async fn one_unavailable_address_is_a_startup_error() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").expect("listener can be bound");
+    let taken = taken
+        .local_addr()
+        .expect("listener has an address")
+        .to_string();
+    let e = serve_error(&[
+        "--bind",
+        &format!("127.0.0.1:0,{taken}"),
+        "--cert",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/server.crt"),
+        "--key",
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/server.key"),
+    ])
+    .await;
+    assert!(e.contains(&taken), "{e}");
+}